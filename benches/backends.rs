@@ -0,0 +1,51 @@
+//! Compares the tree-walking interpreter with the bytecode VM.
+//!
+//! Run with `cargo bench --bench backends`. Each script is parsed once and
+//! run several times on each backend; the fastest and mean run times are
+//! reported. The matrix multiplication case is the same workload the Python
+//! comparison in `pyproject.toml` describes.
+
+mod common;
+
+use common::{bench, FIBONACCI};
+use rustjs::Backend;
+
+const MATRIX: &str = "
+fun matrix(n, seed) {
+    var rows = [];
+    for (var i = 0; i < n; i++) {
+        var row = [];
+        for (var j = 0; j < n; j++) {
+            row.push((i * n + j + seed) % 10);
+        }
+        rows.push(row);
+    }
+    return rows;
+}
+
+fun multiply(a, b, n) {
+    var c = [];
+    for (var i = 0; i < n; i++) {
+        var row = [];
+        for (var j = 0; j < n; j++) {
+            var sum = 0;
+            for (var k = 0; k < n; k++) {
+                sum += a[i][k] * b[k][j];
+            }
+            row.push(sum);
+        }
+        c.push(row);
+    }
+    return c;
+}
+
+var n = 40;
+multiply(matrix(n, 1), matrix(n, 2), n);
+";
+
+fn main() {
+    for backend in [Backend::TreeWalker, Backend::Bytecode] {
+        bench(&format!("fibonacci(20) ({:?})", backend), FIBONACCI, backend);
+        bench(&format!("matrix multiply 40x40 ({:?})", backend), MATRIX, backend);
+    }
+}
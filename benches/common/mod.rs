@@ -0,0 +1,41 @@
+//! Setup shared by the benchmarks: scripts are parsed once and run several
+//! times on a fresh interpreter, and the fastest and mean times are reported.
+
+use rustjs::{Backend, Interpreter, Lexer, Parser, Resolver, Stmt};
+use std::time::{Duration, Instant};
+
+pub const RUNS: usize = 10;
+
+pub const FIBONACCI: &str = "
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+fib(20);
+";
+
+pub fn parse(source: &str) -> Vec<Stmt> {
+    let tokens = Lexer::new(source.to_string()).scan_tokens().expect("benchmark script should lex");
+    let mut statements = Parser::new(tokens).parse().expect("benchmark script should parse");
+    Resolver::new().resolve(&mut statements).expect("benchmark script should resolve");
+    statements
+}
+
+/// Runs `source` `RUNS` times on `backend`, prints the fastest and mean
+/// times under `label` and returns the fastest
+pub fn bench(label: &str, source: &str, backend: Backend) -> Duration {
+    let statements = parse(source);
+    let mut times = Vec::with_capacity(RUNS);
+    for _ in 0..RUNS {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+        let start = Instant::now();
+        interpreter.interpret(statements.clone()).expect("benchmark script should run");
+        times.push(start.elapsed());
+    }
+
+    let fastest = times.iter().min().copied().unwrap_or_default();
+    let mean = times.iter().sum::<Duration>() / RUNS as u32;
+    println!("{:<36} fastest {:>9.2?}   mean {:>9.2?}", label, fastest, mean);
+    fastest
+}
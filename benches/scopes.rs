@@ -0,0 +1,60 @@
+//! Measures the cost of entering scopes and calling functions.
+//!
+//! Run with `cargo bench --bench scopes`. Each script is parsed once and
+//! interpreted several times; the fastest and mean run times are reported.
+//! The two "calls with N globals" cases should take about the same time:
+//! entering a block or a function links a new frame onto the scope chain
+//! instead of copying the scopes around it, so the cost does not grow with
+//! the number of variables already defined. The ratio between the two is
+//! printed last.
+
+mod common;
+
+use common::{bench, FIBONACCI};
+use rustjs::Backend;
+
+const NESTED_BLOCKS: &str = "
+var i = 0;
+while (i < 50000) {
+    {
+        var a = i;
+        {
+            var b = a;
+            {
+                var c = b;
+            }
+        }
+    }
+    i = i + 1;
+}
+";
+
+fn calls_with_globals(globals: usize) -> String {
+    let mut source = String::new();
+    for n in 0..globals {
+        source.push_str(&format!("var global_{} = {};\n", n, n));
+    }
+    source.push_str(
+        "
+fun identity(x) { return x; }
+var i = 0;
+while (i < 20000) {
+    identity(i);
+    i = i + 1;
+}
+",
+    );
+    source
+}
+
+fn main() {
+    bench("fibonacci(20)", FIBONACCI, Backend::TreeWalker);
+    bench("nested blocks x 50000", NESTED_BLOCKS, Backend::TreeWalker);
+    let few = bench("calls with 10 globals", &calls_with_globals(10), Backend::TreeWalker);
+    let many = bench("calls with 2000 globals", &calls_with_globals(2000), Backend::TreeWalker);
+
+    // Copying the scopes around each call would make this grow with the
+    // number of globals; sharing them keeps it near 1x
+    let ratio = many.as_secs_f64() / few.as_secs_f64();
+    println!("2000 vs 10 globals: {:.2}x (expected about 1x)", ratio);
+}
@@ -1,4 +1,4 @@
-use rustjs::{Lexer, Parser, Interpreter};
+use rustjs::{Lexer, Parser, Interpreter, Resolver};
 
 fn main() {
     // Example source code
@@ -43,22 +43,30 @@ fn main() {
             // Create parser and parse
             let mut parser = Parser::new(tokens);
             match parser.parse() {
-                Ok(statements) => {
+                Ok(mut statements) => {
                     println!("Successfully parsed {} statements", statements.len());
 
+                    // Bind variables to their scopes
+                    if let Err(errors) = Resolver::new().resolve(&mut statements) {
+                        for error in errors {
+                            println!("Resolution error: {}", error.render("hello_world", source));
+                        }
+                        return;
+                    }
+
                     // Create interpreter and execute
                     let mut interpreter = Interpreter::new();
                     if let Err(error) = interpreter.interpret(statements) {
-                        println!("Runtime error: {}", error);
+                        println!("Runtime error: {}", error.render("hello_world", source));
                     }
                 }
-                Err(error) => {
-                    println!("Parse error: {}", error);
-                    // Print the current token for debugging
-                    println!("ERROR OCCURRED AT TOKEN NUMBER: {}", parser.current);
+                Err(errors) => {
+                    for error in errors {
+                        println!("Parse error: {}", error.render("hello_world", source));
+                    }
                 }
             }
         }
-        Err(error) => println!("Lexical error: {}", error),
+        Err(error) => println!("Lexical error: {}", error.render("hello_world", source)),
     }
 }
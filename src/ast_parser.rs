@@ -1,4 +1,6 @@
+use crate::error::{Diagnostic, Location};
 use crate::lexer::{Token, TokenType};
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum Expr {
@@ -6,13 +8,68 @@ pub enum Expr {
     Grouping(Box<Expr>),
     Literal(Literal),
     Unary(Token, Box<Expr>),
-    Variable(Token),
-    Assign(Token, Box<Expr>),
+    // A variable and, once resolved, the local it refers to; globals have none
+    Variable(Token, Option<Binding>),
+    Assign(Token, Box<Expr>, Option<Binding>),
     Logical(Box<Expr>, Token, Box<Expr>),
     Call(Box<Expr>, Token, Vec<Expr>),
     Get(Box<Expr>, Token),
     Method(Box<Expr>, Token, Vec<Expr>),
     Array(Vec<Expr>),
+    Object(Vec<(String, Expr)>),
+    Index(Box<Expr>, Token, Box<Expr>),
+    IndexSet(Box<Expr>, Token, Box<Expr>, Box<Expr>),
+    Set(Box<Expr>, Token, Box<Expr>),
+    This(Token, Option<Binding>),
+    // `super.method`, bound to the `super` local of the enclosing class
+    Super(Token, Token, Option<Binding>),
+    Template(Vec<Expr>),
+    Function(Token, Vec<Token>, Rc<Vec<Stmt>>),
+    // `target op= value`, with the compound operator token
+    Compound(Box<Expr>, Token, Box<Expr>),
+    // `++target` / `target--`; the flag is true for the prefix form
+    Update(Box<Expr>, Token, bool),
+    Conditional(Box<Expr>, Token, Box<Expr>, Box<Expr>),
+    // The object of a `?.` access, which ends the enclosing `Chain` when nil
+    Optional(Box<Expr>),
+    // A property/call chain containing `?.`
+    Chain(Box<Expr>),
+}
+
+impl Expr {
+    /// The source position to report for errors raised while evaluating this
+    /// expression. Literal-like nodes without a token of their own have none.
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            Expr::Binary(_, token, _)
+            | Expr::Logical(_, token, _)
+            | Expr::Unary(token, _)
+            | Expr::Variable(token, _)
+            | Expr::Assign(token, _, _)
+            | Expr::Call(_, token, _)
+            | Expr::Get(_, token)
+            | Expr::Method(_, token, _)
+            | Expr::Index(_, token, _)
+            | Expr::IndexSet(_, token, _, _)
+            | Expr::Set(_, token, _)
+            | Expr::This(token, _)
+            | Expr::Super(token, _, _)
+            | Expr::Function(token, _, _)
+            | Expr::Compound(_, token, _)
+            | Expr::Update(_, token, _)
+            | Expr::Conditional(_, token, _, _) => Some(token.location()),
+            Expr::Grouping(expr) | Expr::Optional(expr) | Expr::Chain(expr) => expr.location(),
+            Expr::Literal(_) | Expr::Array(_) | Expr::Object(_) | Expr::Template(_) => None,
+        }
+    }
+}
+
+/// Where the resolver found a local variable: `depth` scopes out from the
+/// scope it is used in, at position `slot` among that scope's locals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Binding {
+    pub depth: usize,
+    pub slot: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -31,13 +88,25 @@ pub enum Stmt {
     Block(Vec<Stmt>),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     While(Expr, Box<Stmt>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    ForIn(Option<Token>, Token, Expr, Box<Stmt>),
     Function(Token, Vec<Token>, Vec<Stmt>),
+    Class(Token, Option<Expr>, Vec<Stmt>),
     Return(Token, Option<Expr>),
+    Break(Token),
+    Continue(Token),
+    Throw(Token, Expr),
+    Try(Vec<Stmt>, Option<(Token, Vec<Stmt>)>, Option<Vec<Stmt>>),
 }
 
 pub struct Parser {
     tokens: Vec<Token>,
     pub current: usize,
+    // Number of loops enclosing the statement being parsed, used to reject
+    // `break` and `continue` outside of a loop
+    loop_depth: usize,
+    // Syntax errors recovered from so far
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
@@ -45,25 +114,79 @@ impl Parser {
         Parser {
             tokens,
             current: 0,
+            loop_depth: 0,
+            diagnostics: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, String> {
+    /// Parse the whole program, returning every syntax error found if there
+    /// were any. Variables are bound by `Resolver::resolve` as a separate step
+    /// before the statements are interpreted.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let (statements, diagnostics) = self.parse_partial();
+        if diagnostics.is_empty() {
+            Ok(statements)
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    /// Parse the whole program, recovering from syntax errors at statement
+    /// boundaries. Returns the statements that parsed, which form a partial
+    /// AST when there are errors, together with the errors themselves.
+    pub fn parse_partial(&mut self) -> (Vec<Stmt>, Vec<Diagnostic>) {
         let mut statements = Vec::new();
 
         // Skip any leading newlines
         self.skip_newlines();
 
         while !self.is_at_end() {
+            let start = self.current;
             match self.declaration() {
                 Ok(stmt) => statements.push(stmt),
-                Err(err) => return Err(err),
+                Err(error) => self.recover(error, start, false),
             }
 
             // Skip any newlines between statements
             self.skip_newlines();
         }
-        Ok(statements)
+        (statements, std::mem::take(&mut self.diagnostics))
+    }
+
+    /// Record `error` and skip ahead to the next statement boundary: past a
+    /// `;` or newline, or up to a `}` or a keyword that starts a statement.
+    fn recover(&mut self, error: Diagnostic, start: usize, in_block: bool) {
+        self.diagnostics.push(error);
+
+        while !self.is_at_end() {
+            match self.peek().token_type {
+                TokenType::Semicolon | TokenType::Newline => {
+                    self.advance();
+                    break;
+                }
+                TokenType::RightBrace
+                | TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Throw
+                | TokenType::Try => break,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+
+        // Always make progress, except at the `}` that ends the enclosing block
+        if self.current == start && !(in_block && self.check(TokenType::RightBrace)) {
+            self.advance();
+        }
     }
 
     fn skip_newlines(&mut self) {
@@ -72,19 +195,48 @@ impl Parser {
         }
     }
 
-    fn declaration(&mut self) -> Result<Stmt, String> {
+    fn declaration(&mut self) -> Result<Stmt, Diagnostic> {
         self.skip_newlines();
 
         if self.match_token(&[TokenType::Var]) {
             return self.var_declaration();
         }
-        if self.match_token(&[TokenType::Fun]) {
+        // `fun (` starts a function expression rather than a declaration
+        if self.check(TokenType::Fun) && self.peek_at(1).token_type != TokenType::LeftParen {
+            self.advance();
             return self.function("function");
         }
+        if self.match_token(&[TokenType::Class]) {
+            return self.class_declaration();
+        }
         self.statement()
     }
 
-    fn var_declaration(&mut self) -> Result<Stmt, String> {
+    fn class_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let name = self.consume(TokenType::Identifier("".to_string()), "Expect class name.")?;
+
+        let superclass = if self.match_token(&[TokenType::Less]) {
+            let superclass = self.consume(TokenType::Identifier("".to_string()), "Expect superclass name.")?;
+            Some(Expr::Variable(superclass, None))
+        } else {
+            None
+        };
+
+        self.skip_newlines();
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        self.skip_newlines();
+
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+            self.skip_newlines();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class(name, superclass, methods))
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, Diagnostic> {
         let name = self.consume(TokenType::Identifier("".to_string()), "Expect variable name.")?;
 
         self.skip_newlines();
@@ -98,22 +250,27 @@ impl Parser {
         self.skip_newlines();
 
         // Handle both semicolon and newline termination
-        if !self.match_token(&[TokenType::Semicolon]) && !self.match_token(&[TokenType::Newline]) {
-            return Err("Expect ';' or newline after variable declaration.".to_string());
-        }
+        self.end_statement("Expect ';' or newline after variable declaration.")?;
 
         Ok(Stmt::Var(name, initializer))
     }
 
-    fn function(&mut self, kind: &str) -> Result<Stmt, String> {
+    fn function(&mut self, kind: &str) -> Result<Stmt, Diagnostic> {
         let name = self.consume(TokenType::Identifier("".to_string()), &format!("Expect {} name.", kind))?;
         self.consume(TokenType::LeftParen, &format!("Expect '(' after {} name.", kind))?;
+        let parameters = self.parameters()?;
+        self.consume(TokenType::LeftBrace, &format!("Expect '{{' before {} body.", kind))?;
+        let body = self.function_body()?;
+        Ok(Stmt::Function(name, parameters, body))
+    }
 
+    /// Parse a parameter list up to and including the closing ')'.
+    fn parameters(&mut self) -> Result<Vec<Token>, Diagnostic> {
         let mut parameters = Vec::new();
         if !self.check(TokenType::RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    return Err("Cannot have more than 255 parameters.".to_string());
+                    return Err(self.error("Cannot have more than 255 parameters."));
                 }
                 parameters.push(self.consume(TokenType::Identifier("".to_string()), "Expect parameter name.")?);
                 if !self.match_token(&[TokenType::Comma]) {
@@ -123,13 +280,64 @@ impl Parser {
         }
 
         self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
-        self.consume(TokenType::LeftBrace, &format!("Expect '{{' before {} body.", kind))?;
+        Ok(parameters)
+    }
 
-        let body = self.block()?;
-        Ok(Stmt::Function(name, parameters, body))
+    /// Parse a function body after its opening '{'.
+    fn function_body(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
+        // A loop around the declaration does not make `break` valid in the body
+        let enclosing_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.block();
+        self.loop_depth = enclosing_loop_depth;
+        body
+    }
+
+    /// Parse `fun (params) { body }` after the `fun` keyword.
+    fn function_expression(&mut self) -> Result<Expr, Diagnostic> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'fun'.")?;
+        let parameters = self.parameters()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        let body = self.function_body()?;
+        Ok(Expr::Function(keyword, parameters, Rc::new(body)))
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    /// Parse `(params) => body` after the opening '('. The body is either a
+    /// block or a single expression whose value is returned.
+    fn arrow_function(&mut self) -> Result<Expr, Diagnostic> {
+        let parameters = self.parameters()?;
+        let arrow = self.consume(TokenType::Arrow, "Expect '=>' after arrow function parameters.")?;
+        self.skip_newlines();
+
+        let body = if self.match_token(&[TokenType::LeftBrace]) {
+            self.function_body()?
+        } else {
+            vec![Stmt::Return(arrow.clone(), Some(self.expression()?))]
+        };
+        Ok(Expr::Function(arrow, parameters, Rc::new(body)))
+    }
+
+    /// After a '(', check whether the tokens ahead form an arrow function's
+    /// parameter list: `)` or identifiers separated by commas, then `) =>`.
+    fn is_arrow_function(&self) -> bool {
+        let mut offset = 0;
+        if !matches!(self.peek_at(0).token_type, TokenType::RightParen) {
+            loop {
+                if !matches!(self.peek_at(offset).token_type, TokenType::Identifier(_)) {
+                    return false;
+                }
+                offset += 1;
+                if self.peek_at(offset).token_type != TokenType::Comma {
+                    break;
+                }
+                offset += 1;
+            }
+        }
+        self.peek_at(offset).token_type == TokenType::RightParen
+            && self.peek_at(offset + 1).token_type == TokenType::Arrow
+    }
+
+    fn statement(&mut self) -> Result<Stmt, Diagnostic> {
         self.skip_newlines();
 
         if self.match_token(&[TokenType::Print]) {
@@ -141,24 +349,73 @@ impl Parser {
         if self.match_token(&[TokenType::While]) {
             return self.while_statement();
         }
+        if self.match_token(&[TokenType::For]) {
+            return self.for_statement();
+        }
         if self.match_token(&[TokenType::Return]) {
             return self.return_statement();
         }
+        if self.match_token(&[TokenType::Break, TokenType::Continue]) {
+            return self.loop_control_statement();
+        }
+        if self.match_token(&[TokenType::Throw]) {
+            return self.throw_statement();
+        }
+        if self.match_token(&[TokenType::Try]) {
+            return self.try_statement();
+        }
         if self.match_token(&[TokenType::LeftBrace]) {
             return Ok(Stmt::Block(self.block()?));
         }
         self.expression_statement()
     }
 
-    fn print_statement(&mut self) -> Result<Stmt, String> {
+    fn throw_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
         let value = self.expression()?;
-        if !self.match_token(&[TokenType::Semicolon]) && !self.match_token(&[TokenType::Newline]) {
-            return Err("Expect ';' or newline after value.".to_string());
+        self.end_statement("Expect ';' or newline after thrown value.")?;
+        Ok(Stmt::Throw(keyword, value))
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.skip_newlines();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+
+        self.skip_newlines();
+        let catch = if self.match_token(&[TokenType::Catch]) {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self.consume(TokenType::Identifier("".to_string()), "Expect exception variable name.")?;
+            self.consume(TokenType::RightParen, "Expect ')' after exception variable.")?;
+            self.skip_newlines();
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.")?;
+            Some((name, self.block()?))
+        } else {
+            None
+        };
+
+        self.skip_newlines();
+        let finally = if self.match_token(&[TokenType::Finally]) {
+            self.skip_newlines();
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(self.error("Expect 'catch' or 'finally' after try block."));
         }
+        Ok(Stmt::Try(body, catch, finally))
+    }
+
+    fn print_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let value = self.expression()?;
+        self.end_statement("Expect ';' or newline after value.")?;
         Ok(Stmt::Print(value))
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, String> {
+    fn if_statement(&mut self) -> Result<Stmt, Diagnostic> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
         let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
@@ -173,36 +430,123 @@ impl Parser {
         Ok(Stmt::If(condition, then_branch, else_branch))
     }
 
-    fn while_statement(&mut self) -> Result<Stmt, String> {
+    fn while_statement(&mut self) -> Result<Stmt, Diagnostic> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
-        let body = Box::new(self.statement()?);
+        let body = Box::new(self.loop_body()?);
         Ok(Stmt::While(condition, body))
     }
 
-    fn return_statement(&mut self) -> Result<Stmt, String> {
-        let keyword = self.previous().clone();
-        let value = if !self.check(TokenType::Semicolon) && !self.check(TokenType::Newline) {
+    fn for_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+
+        if self.is_for_in() {
+            return self.for_in_statement();
+        }
+
+        let initializer = if self.match_token(&[TokenType::Semicolon]) {
+            None
+        } else if self.match_token(&[TokenType::Var]) {
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            Some(Box::new(self.expression_statement()?))
+        };
+
+        let condition = if !self.check(TokenType::Semicolon) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if !self.check(TokenType::RightParen) {
             Some(self.expression()?)
         } else {
             None
         };
-        if !self.match_token(&[TokenType::Semicolon]) && !self.match_token(&[TokenType::Newline]) {
-            return Err("Expect ';' or newline after return value.".to_string());
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let body = Box::new(self.loop_body()?);
+        Ok(Stmt::For(initializer, condition, increment, body))
+    }
+
+    /// Look ahead for `item in` or `index, item in` after the opening parenthesis
+    fn is_for_in(&self) -> bool {
+        let is_identifier = |offset: usize| {
+            matches!(self.peek_at(offset).token_type, TokenType::Identifier(_))
+        };
+        if !is_identifier(0) {
+            return false;
+        }
+        match self.peek_at(1).token_type {
+            TokenType::In => true,
+            TokenType::Comma => is_identifier(2) && self.peek_at(3).token_type == TokenType::In,
+            _ => false,
+        }
+    }
+
+    fn for_in_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let first = self.consume(TokenType::Identifier("".to_string()), "Expect loop variable name.")?;
+        let (index, item) = if self.match_token(&[TokenType::Comma]) {
+            let second = self.consume(TokenType::Identifier("".to_string()), "Expect loop variable name after ','.")?;
+            (Some(first), second)
+        } else {
+            (None, first)
+        };
+
+        self.consume(TokenType::In, "Expect 'in' after loop variable.")?;
+        let iterable = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after for-in clause.")?;
+
+        let body = Box::new(self.loop_body()?);
+        Ok(Stmt::ForIn(index, item, iterable, body))
+    }
+
+    fn loop_body(&mut self) -> Result<Stmt, Diagnostic> {
+        self.loop_depth += 1;
+        let body = self.statement();
+        self.loop_depth -= 1;
+        body
+    }
+
+    fn loop_control_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        if self.loop_depth == 0 {
+            return Err(Self::error_at(&keyword, format!("Can't use '{}' outside of a loop.", keyword.lexeme)));
         }
+        self.end_statement(format!("Expect ';' or newline after '{}'.", keyword.lexeme))?;
+        if keyword.token_type == TokenType::Break {
+            Ok(Stmt::Break(keyword))
+        } else {
+            Ok(Stmt::Continue(keyword))
+        }
+    }
+
+    fn return_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        let value = if !self.check(TokenType::Semicolon)
+            && !self.check(TokenType::Newline)
+            && !self.check(TokenType::RightBrace)
+        {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.end_statement("Expect ';' or newline after return value.")?;
         Ok(Stmt::Return(keyword, value))
     }
 
-    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+    fn block(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
         let mut statements = Vec::new();
 
         self.skip_newlines();
 
         while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            let start = self.current;
             match self.declaration() {
                 Ok(stmt) => statements.push(stmt),
-                Err(err) => return Err(err),
+                Err(error) => self.recover(error, start, true),
             }
 
             self.skip_newlines();
@@ -211,38 +555,109 @@ impl Parser {
         Ok(statements)
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt, String> {
+    fn expression_statement(&mut self) -> Result<Stmt, Diagnostic> {
         let expr = self.expression()?;
-        if !self.match_token(&[TokenType::Semicolon]) && !self.match_token(&[TokenType::Newline]) {
-            return Err("Expect ';' or newline after expression.".to_string());
-        }
+        self.end_statement("Expect ';' or newline after expression.")?;
         Ok(Stmt::Expression(expr))
     }
 
-    fn expression(&mut self) -> Result<Expr, String> {
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
         self.skip_newlines();
         self.assignment()
     }
 
-    fn assignment(&mut self) -> Result<Expr, String> {
+    fn assignment(&mut self) -> Result<Expr, Diagnostic> {
         self.skip_newlines();
-        let expr = self.equality()?;
+        let expr = self.conditional()?;
 
         self.skip_newlines();
         if self.match_token(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
             self.skip_newlines();
-            let _equals = self.previous().clone();
             let value = self.assignment()?;
 
-            if let Expr::Variable(name) = expr {
-                return Ok(Expr::Assign(name, Box::new(value)));
+            return match expr {
+                Expr::Variable(name, _) => Ok(Expr::Assign(name, Box::new(value), None)),
+                Expr::Get(object, name) => Ok(Expr::Set(object, name, Box::new(value))),
+                Expr::Index(object, bracket, index) => Ok(Expr::IndexSet(object, bracket, index, Box::new(value))),
+                _ => Err(Self::error_at(&equals, "Invalid assignment target.")),
+            };
+        }
+
+        if self.match_token(&[
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+            TokenType::PercentEqual,
+        ]) {
+            let operator = self.previous().clone();
+            if !Self::is_assignment_target(&expr) {
+                return Err(Self::error_at(&operator, "Invalid assignment target."));
             }
-            return Err("Invalid assignment target.".to_string());
+            self.skip_newlines();
+            let value = self.assignment()?;
+            return Ok(Expr::Compound(Box::new(expr), operator, Box::new(value)));
         }
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, String> {
+    fn is_assignment_target(expr: &Expr) -> bool {
+        matches!(expr, Expr::Variable(..) | Expr::Get(_, _) | Expr::Index(_, _, _))
+    }
+
+    fn conditional(&mut self) -> Result<Expr, Diagnostic> {
+        let condition = self.coalesce()?;
+
+        self.skip_newlines();
+        if self.match_token(&[TokenType::Question]) {
+            let question = self.previous().clone();
+            let then_branch = self.assignment()?;
+            self.skip_newlines();
+            self.consume(TokenType::Colon, "Expect ':' in conditional expression.")?;
+            let else_branch = self.assignment()?;
+            return Ok(Expr::Conditional(
+                Box::new(condition),
+                question,
+                Box::new(then_branch),
+                Box::new(else_branch),
+            ));
+        }
+        Ok(condition)
+    }
+
+    /// `a ?? b` evaluates to `b` only when `a` is nil.
+    fn coalesce(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.logic_or()?;
+        while self.match_token(&[TokenType::QuestionQuestion]) {
+            let operator = self.previous().clone();
+            let right = self.logic_or()?;
+            expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn logic_or(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.logic_and()?;
+        while self.match_token(&[TokenType::Or]) {
+            let operator = self.previous().clone();
+            let right = self.logic_and()?;
+            expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn logic_and(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.equality()?;
+        while self.match_token(&[TokenType::And]) {
+            let operator = self.previous().clone();
+            let right = self.equality()?;
+            expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.comparison()?;
         while self.match_token(&[TokenType::EqualEqual, TokenType::BangEqual]) {
             let operator = self.previous().clone();
@@ -252,7 +667,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, String> {
+    fn comparison(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.term()?;
         while self.match_token(&[
             TokenType::Greater,
@@ -267,7 +682,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, String> {
+    fn term(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.factor()?;
         while self.match_token(&[TokenType::Minus, TokenType::Plus]) {
             let operator = self.previous().clone();
@@ -277,9 +692,9 @@ impl Parser {
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, String> {
+    fn factor(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.unary()?;
-        while self.match_token(&[TokenType::Slash, TokenType::Star]) {
+        while self.match_token(&[TokenType::Slash, TokenType::Star, TokenType::Percent, TokenType::TildeSlash]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
             expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
@@ -287,7 +702,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr, String> {
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
         self.skip_newlines();
 
         if self.match_token(&[TokenType::Bang, TokenType::Minus]) {
@@ -295,10 +710,43 @@ impl Parser {
             let right = self.unary()?;
             return Ok(Expr::Unary(operator, Box::new(right)));
         }
-        self.call()
+        if self.match_token(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let operator = self.previous().clone();
+            let target = self.unary()?;
+            if !Self::is_assignment_target(&target) {
+                return Err(Self::error_at(&operator, "Invalid increment target."));
+            }
+            return Ok(Expr::Update(Box::new(target), operator, true));
+        }
+        self.power()
     }
 
-    fn primary(&mut self) -> Result<Expr, String> {
+    /// `**` binds tighter than unary minus and groups to the right, so
+    /// `-2 ** 2` is `-(2 ** 2)` and `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+    fn power(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.postfix()?;
+        if self.match_token(&[TokenType::StarStar]) {
+            let operator = self.previous().clone();
+            let right = self.unary()?;
+            return Ok(Expr::Binary(Box::new(expr), operator, Box::new(right)));
+        }
+        Ok(expr)
+    }
+
+    /// A postfix `++`/`--` must follow its operand on the same line.
+    fn postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.call()?;
+        if self.match_token(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let operator = self.previous().clone();
+            if !Self::is_assignment_target(&expr) {
+                return Err(Self::error_at(&operator, "Invalid increment target."));
+            }
+            return Ok(Expr::Update(Box::new(expr), operator, false));
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
         if self.match_token(&[TokenType::False]) {
             return Ok(Expr::Literal(Literal::Boolean(false)));
         }
@@ -316,11 +764,30 @@ impl Parser {
             self.advance();
             return Ok(Expr::Literal(Literal::String(s)));
         }
+        if let TokenType::TemplateStart(s) = self.peek().token_type.clone() {
+            self.advance();
+            return self.template(s);
+        }
         if self.check(TokenType::Identifier("".to_string())) {
             self.advance();
-            return Ok(Expr::Variable(self.previous().clone()));
+            return Ok(Expr::Variable(self.previous().clone(), None));
+        }
+        if self.match_token(&[TokenType::This]) {
+            return Ok(Expr::This(self.previous().clone(), None));
+        }
+        if self.match_token(&[TokenType::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+            let method = self.consume(TokenType::Identifier("".to_string()), "Expect superclass method name.")?;
+            return Ok(Expr::Super(keyword, method, None));
+        }
+        if self.match_token(&[TokenType::Fun]) {
+            return self.function_expression();
         }
         if self.match_token(&[TokenType::LeftParen]) {
+            if self.is_arrow_function() {
+                return self.arrow_function();
+            }
             self.skip_newlines();
             let expr = self.expression()?;
             self.skip_newlines();
@@ -330,10 +797,13 @@ impl Parser {
         if self.match_token(&[TokenType::LeftBracket]) {
             return self.array_literal();
         }
-        Err("Expect expression.".to_string())
+        if self.match_token(&[TokenType::LeftBrace]) {
+            return self.object_literal();
+        }
+        Err(self.error("Expect expression."))
     }
 
-    fn array_literal(&mut self) -> Result<Expr, String> {
+    fn array_literal(&mut self) -> Result<Expr, Diagnostic> {
         self.skip_newlines();
 
         let mut elements = Vec::new();
@@ -356,15 +826,87 @@ impl Parser {
         Ok(Expr::Array(elements))
     }
 
-    fn call(&mut self) -> Result<Expr, String> {
+    fn object_literal(&mut self) -> Result<Expr, Diagnostic> {
+        self.skip_newlines();
+
+        let mut properties = Vec::new();
+
+        while !self.check(TokenType::RightBrace) {
+            // Keys may be bare identifiers or quoted strings
+            let key = match &self.peek().token_type {
+                TokenType::Identifier(name) => name.clone(),
+                TokenType::String(s) => s.clone(),
+                _ => return Err(self.error("Expect property name in object literal.")),
+            };
+            self.advance();
+
+            self.skip_newlines();
+            self.consume(TokenType::Colon, "Expect ':' after property name.")?;
+            self.skip_newlines();
+            properties.push((key, self.expression()?));
+            self.skip_newlines();
+
+            if !self.match_token(&[TokenType::Comma]) {
+                break;
+            }
+
+            self.skip_newlines();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after object properties.")?;
+        Ok(Expr::Object(properties))
+    }
+
+    /// Parse the rest of a template literal whose text before the first
+    /// `${` is `head`, alternating embedded expressions and text pieces.
+    fn template(&mut self, head: String) -> Result<Expr, Diagnostic> {
+        let mut parts = Vec::new();
+        let mut text = head;
+        loop {
+            if !text.is_empty() {
+                parts.push(Expr::Literal(Literal::String(text)));
+            }
+            parts.push(self.expression()?);
+            self.skip_newlines();
+
+            match self.peek().token_type.clone() {
+                TokenType::TemplateMiddle(s) => {
+                    self.advance();
+                    text = s;
+                }
+                TokenType::TemplateEnd(s) => {
+                    self.advance();
+                    if !s.is_empty() {
+                        parts.push(Expr::Literal(Literal::String(s)));
+                    }
+                    return Ok(Expr::Template(parts));
+                }
+                _ => return Err(self.error("Expect '}' after template expression.")),
+            }
+        }
+    }
+
+    fn call(&mut self) -> Result<Expr, Diagnostic> {
         let mut expr = self.primary()?;
+        let mut optional = false;
 
         loop {
             if self.match_token(&[TokenType::LeftParen]) {
                 self.skip_newlines();
                 expr = self.finish_call(expr)?;
-            } else if self.match_token(&[TokenType::Dot]) {
+            } else if self.match_token(&[TokenType::LeftBracket]) {
+                // Index access: value[index]
+                let bracket = self.previous().clone();
+                let index = self.expression()?;
+                self.skip_newlines();
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index(Box::new(expr), bracket, Box::new(index));
+            } else if self.match_token(&[TokenType::Dot, TokenType::QuestionDot]) {
                 // Handle property access and method calls
+                if self.previous().token_type == TokenType::QuestionDot {
+                    optional = true;
+                    expr = Expr::Optional(Box::new(expr));
+                }
                 let name = self.consume(TokenType::Identifier("".to_string()), "Expect property name after '.'.")?;
 
                 if self.match_token(&[TokenType::LeftParen]) {
@@ -389,10 +931,14 @@ impl Parser {
             }
         }
 
+        if optional {
+            // `a?.b.c` is nil as a whole when `a` is nil
+            expr = Expr::Chain(Box::new(expr));
+        }
         Ok(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr) -> Result<Expr, String> {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, Diagnostic> {
         let mut arguments = Vec::new();
 
         self.skip_newlines();
@@ -425,14 +971,46 @@ impl Parser {
         false
     }
 
-    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<Token, String> {
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<Token, Diagnostic> {
         if self.check(token_type) {
             Ok(self.advance().clone())
         } else {
-            Err(message.to_string())
+            Err(self.error(message))
+        }
+    }
+
+    /// Finish a statement at a ';' or newline. A '}' also ends the last
+    /// statement of a block, and is left for the block to consume.
+    fn end_statement(&mut self, message: impl Into<String>) -> Result<(), Diagnostic> {
+        if self.match_token(&[TokenType::Semicolon])
+            || self.match_token(&[TokenType::Newline])
+            || self.check(TokenType::RightBrace)
+        {
+            Ok(())
+        } else {
+            Err(self.error(message))
         }
     }
 
+    /// An error at the current token that also says what was found there,
+    /// e.g. "Expect ')' after arguments, found ';'."
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        let message = message.into();
+        let token = self.peek();
+        let found = match token.token_type {
+            TokenType::EOF => "end of input".to_string(),
+            TokenType::Newline => "end of line".to_string(),
+            _ => format!("'{}'", token.lexeme),
+        };
+        let message = format!("{}, found {}.", message.trim_end_matches('.'), found);
+        Self::error_at(token, message)
+    }
+
+    /// An error pointing at `token` itself.
+    fn error_at(token: &Token, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::at(message, token.line, token.column)
+    }
+
     fn check(&self, token_type: TokenType) -> bool {
         if self.is_at_end() {
             return false;
@@ -458,6 +1036,11 @@ impl Parser {
         &self.tokens[self.current]
     }
 
+    fn peek_at(&self, offset: usize) -> &Token {
+        let index = (self.current + offset).min(self.tokens.len() - 1);
+        &self.tokens[index]
+    }
+
     fn previous(&self) -> &Token {
         &self.tokens[self.current - 1]
     }
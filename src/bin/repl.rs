@@ -1,25 +1,45 @@
-use rustjs::{Lexer, Parser, Interpreter};
+use rustjs::{Backend, Lexer, Parser, Interpreter, Resolver};
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::path::Path;
 
+const USAGE: &str = "Usage: repl [--backend tree|bytecode] [script]";
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
     let mut interpreter = Interpreter::new();
+    let mut file_path = None;
 
-    if args.len() > 1 {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--backend" {
+            match args.next().as_deref() {
+                Some("tree") => interpreter.set_backend(Backend::TreeWalker),
+                Some("bytecode") => interpreter.set_backend(Backend::Bytecode),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(1);
+                }
+            }
+        } else if file_path.is_none() {
+            file_path = Some(arg);
+        } else {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(file_path) = file_path {
         // File mode: execute script from file
-        let file_path = &args[1];
-        if !Path::new(file_path).exists() {
+        if !Path::new(&file_path).exists() {
             eprintln!("Error: File '{}' not found", file_path);
             std::process::exit(1);
         }
 
-        match fs::read_to_string(file_path) {
+        match fs::read_to_string(&file_path) {
             Ok(source) => {
                 println!("Executing script from file: {}", file_path);
-                execute_source(&source, &mut interpreter);
+                execute_source(&file_path, &source, &mut interpreter);
             }
             Err(error) => {
                 eprintln!("Error reading file '{}': {}", file_path, error);
@@ -32,7 +52,8 @@ fn main() {
     }
 }
 
-fn execute_source(source: &str, interpreter: &mut Interpreter) -> bool {
+/// Run `source`, reporting any error against `file` with the offending line.
+fn execute_source(file: &str, source: &str, interpreter: &mut Interpreter) -> bool {
     // Create lexer and tokenize
     let mut lexer = Lexer::new(source.to_string());
     match lexer.scan_tokens() {
@@ -40,25 +61,34 @@ fn execute_source(source: &str, interpreter: &mut Interpreter) -> bool {
             // Create parser and parse
             let mut parser = Parser::new(tokens);
             match parser.parse() {
-                Ok(statements) => {
+                Ok(mut statements) => {
+                    // Bind variables to their scopes
+                    if let Err(errors) = Resolver::new().resolve(&mut statements) {
+                        for error in errors {
+                            println!("Resolution error: {}", error.render(file, source));
+                        }
+                        return false;
+                    }
+
                     // Execute
                     match interpreter.interpret(statements) {
                         Ok(_) => true,
                         Err(error) => {
-                            println!("Runtime error: {}", error);
+                            println!("Runtime error: {}", error.render(file, source));
                             false
                         }
                     }
                 }
-                Err(error) => {
-                    println!("Parse error: {}", error);
-                    println!("ERROR OCCURRED AT TOKEN NUMBER: {}", parser.current);
+                Err(errors) => {
+                    for error in errors {
+                        println!("Parse error: {}", error.render(file, source));
+                    }
                     false
                 }
             }
         }
         Err(error) => {
-            println!("Lexical error: {}", error);
+            println!("Lexical error: {}", error.render(file, source));
             false
         }
     }
@@ -109,7 +139,7 @@ fn repl_mode(interpreter: &mut Interpreter) {
                     match fs::read_to_string(file_path) {
                         Ok(source) => {
                             println!("Executing script from file: {}", file_path);
-                            execute_source(&source, interpreter);
+                            execute_source(file_path, &source, interpreter);
                         }
                         Err(error) => {
                             println!("Error reading file '{}': {}", file_path, error);
@@ -122,6 +152,6 @@ fn repl_mode(interpreter: &mut Interpreter) {
         }
 
         // Execute the input as code
-        execute_source(input, interpreter);
+        execute_source("<repl>", input, interpreter);
     }
 }
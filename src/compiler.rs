@@ -0,0 +1,932 @@
+//! Compiles a resolved program to bytecode for the stack machine in
+//! `interpreter::vm`.
+//!
+//! A `Chunk` holds the instructions of one function body, or of the top-level
+//! script, together with the constants, names and nested functions they
+//! refer to by index. Locals keep the (depth, slot) bindings the resolver gave
+//! them, so the compiled code uses the same scope chain as the tree-walking
+//! interpreter and closures behave the same under both.
+
+use crate::ast_parser::{Binding, Expr, Literal, Stmt};
+use crate::error::Location;
+use crate::interpreter::Value;
+use crate::lexer::{Token, TokenType};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// One instruction. Operands index the pools of the chunk the instruction
+/// belongs to: `constants`, `names`, `functions`, `classes` and `paths`;
+/// jump targets are instruction indexes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Constant(u32),
+    Nil,
+    True,
+    False,
+    Pop,
+    Dup,
+    // Locals, by the name for errors and the resolver's binding
+    GetLocal(u32, u16, u16),
+    SetLocal(u32, u16, u16),
+    DefineLocal,
+    GetGlobal(u32),
+    SetGlobal(u32),
+    DefineGlobal(u32),
+    // Push the global whose name is the string constant, or jump if undefined
+    GetQualified(u32, u32),
+    GetProperty(u32),
+    Index,
+    // Read or write the end of an assignment path, see `Chunk::paths`.
+    // `SetPath` leaves the result, the updated base and whether the base
+    // was replaced rather than updated in place.
+    GetPath(u32),
+    SetPath(u32, bool),
+    Binary(BinaryOp),
+    Negate,
+    Not,
+    Increment,
+    Decrement,
+    Print,
+    Jump(u32),
+    JumpIfFalse(u32),
+    // Jump keeping the operand when it decides the result, else pop it
+    And(u32),
+    Or(u32),
+    Coalesce(u32),
+    JumpIfNil(u32),
+    // Call with the number of arguments. The call site is the instruction's
+    // location.
+    Call(u32),
+    Invoke(u32, u32),
+    Closure(u32),
+    Class(u32, bool),
+    Super(u32, u16),
+    Array(u32),
+    Object(u32),
+    Template(u32),
+    EnterScope,
+    ExitScope,
+    ForInPrepare(bool),
+    ForInNext(u32, bool),
+    // Catch errors raised before the matching `PopHandler` at the target,
+    // as a value for a catch clause or as a pending error for a finally block
+    Try(u32),
+    TryFinally(u32),
+    PopHandler,
+    // Run the finally block at the target, then resume after this
+    // instruction, taking the value on top of the stack along if asked to
+    CallFinally(u32, bool),
+    // End a finally block: resume where `CallFinally` left off, or rethrow
+    // the pending error
+    EndFinally,
+    // Forget why the finally block being left was entered
+    DropCompletion,
+    Throw,
+    Fail(u32),
+    Return,
+}
+
+/// The operator of a `Binary` instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    FloorDivide,
+    Power,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl BinaryOp {
+    fn from_token(token_type: &TokenType) -> Option<BinaryOp> {
+        Some(match token_type {
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Subtract,
+            TokenType::Star => BinaryOp::Multiply,
+            TokenType::Slash => BinaryOp::Divide,
+            TokenType::Percent => BinaryOp::Modulo,
+            TokenType::TildeSlash => BinaryOp::FloorDivide,
+            TokenType::StarStar => BinaryOp::Power,
+            TokenType::EqualEqual => BinaryOp::Equal,
+            TokenType::BangEqual => BinaryOp::NotEqual,
+            TokenType::Less => BinaryOp::Less,
+            TokenType::LessEqual => BinaryOp::LessEqual,
+            TokenType::Greater => BinaryOp::Greater,
+            TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+            _ => return None,
+        })
+    }
+
+    /// The token of the operator, as the tree-walking interpreter sees it
+    pub fn token_type(self) -> TokenType {
+        match self {
+            BinaryOp::Add => TokenType::Plus,
+            BinaryOp::Subtract => TokenType::Minus,
+            BinaryOp::Multiply => TokenType::Star,
+            BinaryOp::Divide => TokenType::Slash,
+            BinaryOp::Modulo => TokenType::Percent,
+            BinaryOp::FloorDivide => TokenType::TildeSlash,
+            BinaryOp::Power => TokenType::StarStar,
+            BinaryOp::Equal => TokenType::EqualEqual,
+            BinaryOp::NotEqual => TokenType::BangEqual,
+            BinaryOp::Less => TokenType::Less,
+            BinaryOp::LessEqual => TokenType::LessEqual,
+            BinaryOp::Greater => TokenType::Greater,
+            BinaryOp::GreaterEqual => TokenType::GreaterEqual,
+        }
+    }
+}
+
+/// A step of an assignment path such as `a.b[i]`. Index values are taken
+/// from the stack, in order, below the path's base value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathStep {
+    Field(String),
+    Index,
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    // The source position of the instructions from each index on, up to the
+    // next entry, reported for errors and as the line of call sites
+    locations: Vec<(usize, Option<Location>)>,
+    pub constants: Vec<Value>,
+    // Names of variables, properties and methods
+    pub names: Vec<String>,
+    pub functions: Vec<Rc<Prototype>>,
+    pub classes: Vec<ClassPrototype>,
+    pub paths: Vec<Vec<PathStep>>,
+}
+
+impl Chunk {
+    /// The source position of the instruction at `ip`.
+    pub fn location(&self, ip: usize) -> Option<Location> {
+        let next = self.locations.partition_point(|(start, _)| *start <= ip);
+        next.checked_sub(1).and_then(|entry| self.locations[entry].1)
+    }
+}
+
+/// A compiled function, from which closures are made at run time.
+#[derive(Debug)]
+pub struct Prototype {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub chunk: Rc<Chunk>,
+}
+
+#[derive(Debug)]
+pub struct ClassPrototype {
+    pub name: Token,
+    pub methods: Vec<Rc<Prototype>>,
+}
+
+// Constructs enclosing the code being compiled that a `break`, `continue` or
+// `return` has to unwind on its way out
+enum Region {
+    Scope,
+    Handler,
+    // A try statement with a finally block, which every way out of the
+    // statement calls into. The calls are pointed at the block once it is
+    // compiled.
+    Finally { calls: Vec<usize> },
+    // A finally block, which records why it was entered
+    Completion,
+    Loop { breaks: Vec<usize>, continues: Vec<usize> },
+}
+
+pub struct Compiler {
+    chunk: Chunk,
+    // Where each name is in `chunk.names`
+    names: HashMap<String, u32>,
+    // Location given to the instructions being emitted
+    location: Option<Location>,
+    // Number of scopes the code being compiled is nested in; zero at the top level
+    scope_depth: usize,
+    regions: Vec<Region>,
+    // Jumps to the end of each optional chain being compiled
+    chains: Vec<Vec<usize>>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            chunk: Chunk::default(),
+            names: HashMap::new(),
+            location: None,
+            scope_depth: 0,
+            regions: Vec::new(),
+            chains: Vec::new(),
+        }
+    }
+
+    /// Compile a top-level program.
+    pub fn compile(mut self, statements: &[Stmt]) -> Chunk {
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.emit(Op::Nil);
+        self.emit(Op::Return);
+        self.chunk
+    }
+
+    fn function(name: &Token, params: &[Token], body: &[Stmt]) -> Rc<Prototype> {
+        // The body runs in the scope that holds the parameters
+        let mut compiler = Compiler::new();
+        compiler.scope_depth = 1;
+        for statement in body {
+            compiler.statement(statement);
+        }
+        compiler.emit(Op::Nil);
+        compiler.emit(Op::Return);
+        Rc::new(Prototype {
+            name: name.clone(),
+            params: params.to_vec(),
+            chunk: Rc::new(compiler.chunk),
+        })
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Print(expr) => {
+                self.expression(expr);
+                self.emit(Op::Print);
+            }
+            Stmt::Var(name, initializer) => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(Op::Nil),
+                }
+                self.define(name);
+            }
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let to_else = self.emit_jump(Op::JumpIfFalse(0));
+                self.statement(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let to_end = self.emit_jump(Op::Jump(0));
+                        self.patch(to_else);
+                        self.statement(else_branch);
+                        self.patch(to_end);
+                    }
+                    None => self.patch(to_else),
+                }
+            }
+            Stmt::While(condition, body) => {
+                let start = self.chunk.code.len();
+                self.expression(condition);
+                let to_exit = self.emit_jump(Op::JumpIfFalse(0));
+                self.loop_body(body, start);
+                self.emit(Op::Jump(index(start)));
+                self.patch(to_exit);
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                // The loop gets its own scope so the initializer variable does
+                // not leak into the surrounding code
+                self.enter_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                let start = self.chunk.code.len();
+                let to_exit = condition.as_ref().map(|condition| {
+                    self.expression(condition);
+                    self.emit_jump(Op::JumpIfFalse(0))
+                });
+
+                self.regions.push(Region::Loop { breaks: Vec::new(), continues: Vec::new() });
+                self.statement(body);
+                let (breaks, continues) = self.pop_loop();
+                for jump in continues {
+                    self.patch(jump);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                    self.emit(Op::Pop);
+                }
+                self.emit(Op::Jump(index(start)));
+
+                if let Some(to_exit) = to_exit {
+                    self.patch(to_exit);
+                }
+                for jump in breaks {
+                    self.patch(jump);
+                }
+                self.exit_scope();
+            }
+            Stmt::ForIn(index_name, _, iterable, body) => {
+                self.expression(iterable);
+                self.with_location(iterable.location(), |compiler| {
+                    compiler.emit(Op::ForInPrepare(index_name.is_some()))
+                });
+                // Each iteration runs in a fresh scope holding the loop variables
+                let next = self.emit_jump(Op::ForInNext(0, index_name.is_some()));
+                self.scope_depth += 1;
+                self.regions.push(Region::Loop { breaks: Vec::new(), continues: Vec::new() });
+                self.regions.push(Region::Scope);
+                self.statement(body);
+                self.regions.pop();
+                let (breaks, continues) = self.pop_loop();
+                self.scope_depth -= 1;
+                self.emit(Op::ExitScope);
+                self.emit(Op::Jump(index(next)));
+                for jump in continues {
+                    self.patch_to(jump, next);
+                }
+
+                self.patch(next);
+                for jump in breaks {
+                    self.patch(jump);
+                }
+                // Drop the positions, elements and counter of the loop
+                for _ in 0..3 {
+                    self.emit(Op::Pop);
+                }
+            }
+            Stmt::Function(name, params, body) => {
+                let prototype = Self::function(name, params, body);
+                self.chunk.functions.push(prototype);
+                self.emit(Op::Closure(index(self.chunk.functions.len() - 1)));
+                self.define(name);
+            }
+            Stmt::Class(name, superclass, methods) => {
+                if let Some(superclass) = superclass {
+                    self.expression(superclass);
+                }
+                let methods = methods
+                    .iter()
+                    .filter_map(|method| match method {
+                        Stmt::Function(method_name, params, body) => {
+                            Some(Self::function(method_name, params, body))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                self.chunk.classes.push(ClassPrototype { name: name.clone(), methods });
+                let class = index(self.chunk.classes.len() - 1);
+                let location = superclass.as_ref().and_then(Expr::location);
+                self.with_location(location, |compiler| compiler.emit(Op::Class(class, superclass.is_some())));
+                self.define(name);
+            }
+            Stmt::Return(_, value) => {
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.emit(Op::Nil),
+                }
+                self.unwind(0, true);
+                self.emit(Op::Return);
+            }
+            Stmt::Break(_) | Stmt::Continue(_) => {
+                let Some(target) = self.regions.iter().rposition(|region| matches!(region, Region::Loop { .. })) else {
+                    return;
+                };
+                self.unwind(target + 1, false);
+                let jump = self.emit_jump(Op::Jump(0));
+                if let Some(Region::Loop { breaks, continues }) = self.regions.get_mut(target) {
+                    if matches!(stmt, Stmt::Break(_)) {
+                        breaks.push(jump);
+                    } else {
+                        continues.push(jump);
+                    }
+                }
+            }
+            Stmt::Throw(keyword, value) => {
+                self.expression(value);
+                self.with_location(Some(keyword.location()), |compiler| compiler.emit(Op::Throw));
+            }
+            Stmt::Try(body, catch, finally) => self.try_statement(body, catch.as_ref(), finally.as_deref()),
+        }
+    }
+
+    fn try_statement(&mut self, body: &[Stmt], catch: Option<&(Token, Vec<Stmt>)>, finally: Option<&[Stmt]>) {
+        if finally.is_some() {
+            self.regions.push(Region::Finally { calls: Vec::new() });
+        }
+
+        let handler = match catch {
+            Some(_) => self.emit_jump(Op::Try(0)),
+            None => self.emit_jump(Op::TryFinally(0)),
+        };
+        self.regions.push(Region::Handler);
+        self.block(body);
+        self.regions.pop();
+        self.emit(Op::PopHandler);
+        if finally.is_some() {
+            self.call_finally(false);
+        }
+        let to_done = self.emit_jump(Op::Jump(0));
+        self.patch(handler);
+
+        // An error in the body lands here, with the exception as the value
+        // bound by the catch clause or pending for the finally block
+        let mut to_finally_error = None;
+        if let Some((_, handler)) = catch {
+            if finally.is_some() {
+                to_finally_error = Some(self.emit_jump(Op::TryFinally(0)));
+                self.regions.push(Region::Handler);
+            }
+            self.enter_scope();
+            self.emit(Op::DefineLocal);
+            for statement in handler {
+                self.statement(statement);
+            }
+            self.exit_scope();
+            if finally.is_some() {
+                self.regions.pop();
+                self.emit(Op::PopHandler);
+                self.call_finally(false);
+            }
+        } else {
+            to_finally_error = Some(handler);
+        }
+
+        // Every way out of the try statement runs the one copy of the
+        // finally block, which then carries on where it was called from or
+        // rethrows the pending error
+        if let Some(finally) = finally {
+            let to_end = self.emit_jump(Op::Jump(0));
+            let Some(Region::Finally { calls }) = self.regions.pop() else {
+                unreachable!("finally region not innermost");
+            };
+            for jump in calls {
+                self.patch(jump);
+            }
+            if let Some(jump) = to_finally_error {
+                self.patch(jump);
+            }
+            self.regions.push(Region::Completion);
+            self.block(finally);
+            self.regions.pop();
+            self.emit(Op::EndFinally);
+            self.patch(to_end);
+        }
+        self.patch(to_done);
+    }
+
+    /// Emit a call to the finally block of the innermost region, to be
+    /// pointed at the block once it is compiled.
+    fn call_finally(&mut self, with_value: bool) {
+        let jump = self.emit_jump(Op::CallFinally(0, with_value));
+        if let Some(Region::Finally { calls }) = self.regions.last_mut() {
+            calls.push(jump);
+        }
+    }
+
+    /// Emit the code that leaves every region from the innermost down to
+    /// `regions[depth]`, running the finally blocks on the way. A `return`
+    /// takes its value along through them.
+    fn unwind(&mut self, depth: usize, with_value: bool) {
+        for position in (depth..self.regions.len()).rev() {
+            match &mut self.regions[position] {
+                Region::Scope => self.emit(Op::ExitScope),
+                Region::Handler => self.emit(Op::PopHandler),
+                Region::Completion => self.emit(Op::DropCompletion),
+                Region::Finally { calls } => {
+                    let jump = self.chunk.code.len();
+                    calls.push(jump);
+                    self.emit(Op::CallFinally(0, with_value));
+                }
+                Region::Loop { .. } => {}
+            }
+        }
+    }
+
+    fn loop_body(&mut self, body: &Stmt, start: usize) {
+        self.regions.push(Region::Loop { breaks: Vec::new(), continues: Vec::new() });
+        self.statement(body);
+        let (breaks, continues) = self.pop_loop();
+        for jump in continues {
+            self.patch_to(jump, start);
+        }
+        // Breaks jump past the back edge emitted after the body
+        let exit = self.chunk.code.len() + 1;
+        for jump in breaks {
+            self.patch_to(jump, exit);
+        }
+    }
+
+    fn pop_loop(&mut self) -> (Vec<usize>, Vec<usize>) {
+        match self.regions.pop() {
+            Some(Region::Loop { breaks, continues }) => (breaks, continues),
+            _ => unreachable!("loop region not innermost"),
+        }
+    }
+
+    fn block(&mut self, statements: &[Stmt]) {
+        self.enter_scope();
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.exit_scope();
+    }
+
+    fn enter_scope(&mut self) {
+        self.emit(Op::EnterScope);
+        self.regions.push(Region::Scope);
+        self.scope_depth += 1;
+    }
+
+    fn exit_scope(&mut self) {
+        self.scope_depth -= 1;
+        self.regions.pop();
+        self.emit(Op::ExitScope);
+    }
+
+    /// Define the variable `name` with the value on top of the stack.
+    fn define(&mut self, name: &Token) {
+        if self.scope_depth > 0 {
+            self.emit(Op::DefineLocal);
+        } else {
+            let name = self.name(&name.lexeme);
+            self.emit(Op::DefineGlobal(name));
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        let location = expr.location().or(self.location);
+        self.with_location(location, |compiler| compiler.expression_at(expr));
+    }
+
+    fn expression_at(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(literal) => match literal {
+                Literal::Number(n) => self.constant(Value::Number(*n)),
+                Literal::String(s) => self.constant(Value::String(s.clone())),
+                Literal::Boolean(true) => self.emit(Op::True),
+                Literal::Boolean(false) => self.emit(Op::False),
+                Literal::Nil => self.emit(Op::Nil),
+            },
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Unary(operator, right) => {
+                self.expression(right);
+                if operator.token_type == TokenType::Minus {
+                    self.emit(Op::Negate);
+                } else {
+                    self.emit(Op::Not);
+                }
+            }
+            Expr::Binary(left, operator, right) => {
+                self.expression(left);
+                self.expression(right);
+                match BinaryOp::from_token(&operator.token_type) {
+                    Some(operator) => self.emit(Op::Binary(operator)),
+                    None => self.fail("Invalid binary operator."),
+                }
+            }
+            Expr::Variable(name, binding) => self.get_variable(name, *binding),
+            Expr::Assign(name, value, binding) => {
+                self.expression(value);
+                self.set_variable(name, *binding);
+            }
+            Expr::Logical(left, operator, right) => {
+                self.expression(left);
+                let jump = match operator.token_type {
+                    TokenType::Or => Op::Or(0),
+                    TokenType::And => Op::And(0),
+                    _ => Op::Coalesce(0),
+                };
+                let to_end = self.emit_jump(jump);
+                self.expression(right);
+                self.patch(to_end);
+            }
+            Expr::Conditional(condition, _, then_branch, else_branch) => {
+                self.expression(condition);
+                let to_else = self.emit_jump(Op::JumpIfFalse(0));
+                self.expression(then_branch);
+                let to_end = self.emit_jump(Op::Jump(0));
+                self.patch(to_else);
+                self.expression(else_branch);
+                self.patch(to_end);
+            }
+            Expr::Optional(object) => {
+                self.expression(object);
+                if !self.chains.is_empty() {
+                    let jump = self.emit_jump(Op::JumpIfNil(0));
+                    if let Some(chain) = self.chains.last_mut() {
+                        chain.push(jump);
+                    }
+                }
+            }
+            Expr::Chain(chain) => {
+                self.chains.push(Vec::new());
+                self.expression(chain);
+                for jump in self.chains.pop().unwrap_or_default() {
+                    self.patch(jump);
+                }
+            }
+            Expr::Call(callee, _, arguments) => {
+                self.expression(callee);
+                self.arguments(arguments);
+                self.emit(Op::Call(index(arguments.len())));
+            }
+            Expr::Get(object, name) => {
+                // `Namespace.function` names a global of that name
+                let to_end = if let Expr::Variable(namespace, None) = &**object {
+                    let to_property = self.qualified(namespace, name);
+                    let to_end = self.emit_jump(Op::Jump(0));
+                    self.patch(to_property);
+                    Some(to_end)
+                } else {
+                    None
+                };
+                self.expression(object);
+                let name = self.name(&name.lexeme);
+                self.emit(Op::GetProperty(name));
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+            }
+            Expr::Method(object, name, arguments) => {
+                let to_end = if let Expr::Variable(namespace, None) = &**object {
+                    let to_method = self.qualified(namespace, name);
+                    self.arguments(arguments);
+                    self.emit(Op::Call(index(arguments.len())));
+                    let to_end = self.emit_jump(Op::Jump(0));
+                    self.patch(to_method);
+                    Some(to_end)
+                } else {
+                    None
+                };
+                self.expression(object);
+                self.arguments(arguments);
+                let name = self.name(&name.lexeme);
+                self.emit(Op::Invoke(name, index(arguments.len())));
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+            }
+            Expr::Array(elements) => {
+                self.arguments(elements);
+                self.emit(Op::Array(index(elements.len())));
+            }
+            Expr::Object(properties) => {
+                for (key, value) in properties {
+                    self.constant(Value::String(key.clone()));
+                    self.expression(value);
+                }
+                self.emit(Op::Object(index(properties.len())));
+            }
+            Expr::Template(parts) => {
+                self.arguments(parts);
+                self.emit(Op::Template(index(parts.len())));
+            }
+            Expr::Function(keyword, params, body) => {
+                let name = Token::new(
+                    TokenType::Identifier("anonymous".to_string()),
+                    "anonymous".to_string(),
+                    None,
+                    keyword.line,
+                    keyword.column,
+                );
+                self.chunk.functions.push(Self::function(&name, params, body));
+                self.emit(Op::Closure(index(self.chunk.functions.len() - 1)));
+            }
+            Expr::Index(object, _, index_expr) => {
+                self.expression(object);
+                self.expression(index_expr);
+                self.emit(Op::Index);
+            }
+            Expr::Set(object, name, value) => {
+                let mut steps = Vec::new();
+                let base = self.path(object, &mut steps);
+                steps.push(PathStep::Field(name.lexeme.clone()));
+                self.store(base, steps, value);
+            }
+            Expr::IndexSet(object, _, index_expr, value) => {
+                let mut steps = Vec::new();
+                let base = self.path(object, &mut steps);
+                self.expression(index_expr);
+                steps.push(PathStep::Index);
+                self.store(base, steps, value);
+            }
+            Expr::Compound(target, operator, value) => {
+                let operator = match operator.token_type {
+                    TokenType::PlusEqual => BinaryOp::Add,
+                    TokenType::MinusEqual => BinaryOp::Subtract,
+                    TokenType::StarEqual => BinaryOp::Multiply,
+                    TokenType::SlashEqual => BinaryOp::Divide,
+                    _ => BinaryOp::Modulo,
+                };
+                self.update(target, false, |compiler| {
+                    compiler.expression(value);
+                    compiler.emit(Op::Binary(operator));
+                });
+            }
+            Expr::Update(target, operator, prefix) => {
+                let step = if operator.token_type == TokenType::PlusPlus { Op::Increment } else { Op::Decrement };
+                self.update(target, !prefix, |compiler| compiler.emit(step));
+            }
+            Expr::This(_, binding) => match binding {
+                Some(binding) => {
+                    let (depth, slot) = (narrow(binding.depth), narrow(binding.slot));
+                    let name = self.name("this");
+                    self.emit(Op::GetLocal(name, depth, slot));
+                }
+                None => self.fail("Can't use 'this' outside of a class method."),
+            },
+            Expr::Super(_, method, binding) => match binding {
+                Some(binding) => {
+                    let method = self.name(&method.lexeme);
+                    self.emit(Op::Super(method, narrow(binding.depth)));
+                }
+                None => self.fail("Can't use 'super' outside of a subclass method."),
+            },
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Expr]) {
+        for argument in arguments {
+            self.expression(argument);
+        }
+    }
+
+    /// Emit the lookup of the global `namespace.name`, returning the jump taken
+    /// when there is no such global.
+    fn qualified(&mut self, namespace: &Token, name: &Token) -> usize {
+        let qualified = format!("{}.{}", namespace.lexeme, name.lexeme);
+        self.chunk.constants.push(Value::String(qualified));
+        let constant = index(self.chunk.constants.len() - 1);
+        self.emit_jump(Op::GetQualified(constant, 0))
+    }
+
+    /// Compile the base of an assignment target such as `a.b[i]`, then the
+    /// index expressions along it, collecting its steps, and return the base
+    /// expression.
+    fn path<'e>(&mut self, expr: &'e Expr, steps: &mut Vec<PathStep>) -> &'e Expr {
+        match expr {
+            Expr::Get(object, name) => {
+                let base = self.path(object, steps);
+                steps.push(PathStep::Field(name.lexeme.clone()));
+                base
+            }
+            Expr::Index(object, _, index_expr) => {
+                let base = self.path(object, steps);
+                self.expression(index_expr);
+                steps.push(PathStep::Index);
+                base
+            }
+            _ => {
+                self.expression(expr);
+                expr
+            }
+        }
+    }
+
+    /// Store `value` at the end of `steps` from `base`, whose value and index
+    /// values are on the stack, leaving `value` as the result.
+    fn store(&mut self, base: &Expr, steps: Vec<PathStep>, value: &Expr) {
+        self.expression(value);
+        self.chunk.paths.push(steps);
+        self.emit(Op::SetPath(index(self.chunk.paths.len() - 1), false));
+        self.store_base(base);
+    }
+
+    /// Replace the value of an assignment target with the result of the code
+    /// `apply` emits, which turns the current value into the new one. The
+    /// result is the old value for a postfix update, else the new one.
+    fn update(&mut self, target: &Expr, postfix: bool, apply: impl FnOnce(&mut Self)) {
+        if let Expr::Variable(name, binding) = target {
+            self.get_variable(name, *binding);
+            if postfix {
+                self.emit(Op::Dup);
+            }
+            apply(self);
+            self.set_variable(name, *binding);
+            if postfix {
+                self.emit(Op::Pop);
+            }
+            return;
+        }
+
+        let mut steps = Vec::new();
+        let base = self.path(target, &mut steps);
+        self.chunk.paths.push(steps);
+        let path = index(self.chunk.paths.len() - 1);
+        self.emit(Op::GetPath(path));
+        if postfix {
+            self.emit(Op::Dup);
+        }
+        apply(self);
+        self.emit(Op::SetPath(path, postfix));
+        self.store_base(base);
+    }
+
+    /// Write the updated base value `SetPath` leaves on the stack back to the
+    /// variable the path starts from, if the base was replaced, and drop it.
+    fn store_base(&mut self, base: &Expr) {
+        if let Expr::Variable(name, binding) = base {
+            let to_kept = self.emit_jump(Op::JumpIfFalse(0));
+            self.set_variable(name, *binding);
+            self.patch(to_kept);
+        } else {
+            self.emit(Op::Pop);
+        }
+        self.emit(Op::Pop);
+    }
+
+    fn get_variable(&mut self, name: &Token, binding: Option<Binding>) {
+        let name = self.name(&name.lexeme);
+        match binding {
+            Some(binding) => self.emit(Op::GetLocal(name, narrow(binding.depth), narrow(binding.slot))),
+            None => self.emit(Op::GetGlobal(name)),
+        }
+    }
+
+    fn set_variable(&mut self, name: &Token, binding: Option<Binding>) {
+        let name = self.name(&name.lexeme);
+        match binding {
+            Some(binding) => self.emit(Op::SetLocal(name, narrow(binding.depth), narrow(binding.slot))),
+            None => self.emit(Op::SetGlobal(name)),
+        }
+    }
+
+    fn fail(&mut self, message: &str) {
+        self.chunk.constants.push(Value::String(message.to_string()));
+        self.emit(Op::Fail(index(self.chunk.constants.len() - 1)));
+    }
+
+    fn constant(&mut self, value: Value) {
+        self.chunk.constants.push(value);
+        self.emit(Op::Constant(index(self.chunk.constants.len() - 1)));
+    }
+
+    /// The index of `name` in the chunk's names, adding it the first time.
+    fn name(&mut self, name: &str) -> u32 {
+        if let Some(&position) = self.names.get(name) {
+            return position;
+        }
+        self.chunk.names.push(name.to_string());
+        let position = index(self.chunk.names.len() - 1);
+        self.names.insert(name.to_string(), position);
+        position
+    }
+
+    fn with_location(&mut self, location: Option<Location>, f: impl FnOnce(&mut Self)) {
+        let enclosing = std::mem::replace(&mut self.location, location);
+        f(self);
+        self.location = enclosing;
+    }
+
+    fn emit(&mut self, op: Op) {
+        // Only record where the location changes
+        if self.chunk.locations.last().map(|(_, location)| *location) != Some(self.location) {
+            self.chunk.locations.push((self.chunk.code.len(), self.location));
+        }
+        self.chunk.code.push(op);
+    }
+
+    /// Emit a jump whose target is filled in later by `patch`.
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit(op);
+        self.chunk.code.len() - 1
+    }
+
+    /// Point the jump at `position` to the next instruction to be emitted.
+    fn patch(&mut self, position: usize) {
+        self.patch_to(position, self.chunk.code.len());
+    }
+
+    fn patch_to(&mut self, position: usize, target: usize) {
+        let target = index(target);
+        self.chunk.code[position] = match self.chunk.code[position] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::And(_) => Op::And(target),
+            Op::Or(_) => Op::Or(target),
+            Op::Coalesce(_) => Op::Coalesce(target),
+            Op::JumpIfNil(_) => Op::JumpIfNil(target),
+            Op::GetQualified(name, _) => Op::GetQualified(name, target),
+            Op::ForInNext(_, with_index) => Op::ForInNext(target, with_index),
+            Op::Try(_) => Op::Try(target),
+            Op::TryFinally(_) => Op::TryFinally(target),
+            Op::CallFinally(_, with_value) => Op::CallFinally(target, with_value),
+            op => unreachable!("{:?} is not a jump", op),
+        };
+    }
+}
+
+fn index(position: usize) -> u32 {
+    u32::try_from(position).expect("chunk too large for 32-bit operands")
+}
+
+fn narrow(position: usize) -> u16 {
+    u16::try_from(position).expect("too many nested scopes or locals for 16-bit operands")
+}
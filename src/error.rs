@@ -0,0 +1,181 @@
+use std::fmt;
+
+/// A 1-based position in the source text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn new(line: usize, column: usize) -> Self {
+        Location { line, column }
+    }
+}
+
+/// A function call that was in progress when a runtime error was raised: the
+/// function's name and the line of the call that entered it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub function: String,
+    pub line: usize,
+}
+
+// Identical consecutive frames beyond this many are summarized in one line
+const MAX_REPEATED_FRAMES: usize = 3;
+
+/// An error reported by the lexer, parser or interpreter, with the place in
+/// the source it refers to when one is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub location: Option<Location>,
+    /// For runtime errors, the calls that were active, outermost first
+    pub call_stack: Vec<CallFrame>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, location: Option<Location>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            location,
+            call_stack: Vec::new(),
+        }
+    }
+
+    pub fn at(message: impl Into<String>, line: usize, column: usize) -> Self {
+        Self::new(message, Some(Location::new(line, column)))
+    }
+
+    /// Format the error as `file:line:column: message`, followed by the
+    /// offending source line with a caret under the reported column and the
+    /// call stack, most recent call first.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut output = match self.location {
+            Some(location) => format!("{}:{}:{}: {}", file, location.line, location.column, self.message),
+            None => format!("{}: {}", file, self.message),
+        };
+        if let Some(location) = self.location {
+            self.render_source_line(&mut output, location, source);
+        }
+
+        if !self.call_stack.is_empty() {
+            output.push_str("\nCall stack (most recent call first):");
+            self.render_call_stack(&mut output, file);
+        }
+        output
+    }
+
+    fn render_call_stack(&self, output: &mut String, file: &str) {
+        let mut frames = self.call_stack.iter().rev().peekable();
+        while let Some(frame) = frames.next() {
+            // Runaway recursion repeats the same frame thousands of times
+            let mut repeats = 0;
+            while frames.peek() == Some(&frame) {
+                frames.next();
+                repeats += 1;
+            }
+            let line = format!("\n  in {}() called from {}:{}", frame.function, file, frame.line);
+            if repeats <= MAX_REPEATED_FRAMES {
+                output.push_str(&line.repeat(repeats + 1));
+            } else {
+                output.push_str(&line.repeat(MAX_REPEATED_FRAMES));
+                output.push_str(&format!(
+                    "\n  [previous frame repeated {} more times]",
+                    repeats + 1 - MAX_REPEATED_FRAMES
+                ));
+            }
+        }
+    }
+
+    fn render_source_line(&self, output: &mut String, location: Location, source: &str) {
+        if let Some(text) = source.lines().nth(location.line.saturating_sub(1)) {
+            let gutter = location.line.to_string();
+            // Keep tabs so the caret lines up with the source as displayed
+            let padding: String = text
+                .chars()
+                .take(location.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            output.push_str(&format!("\n {} | {}", gutter, text));
+            output.push_str(&format!("\n {} | {}^", " ".repeat(gutter.len()), padding));
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(location) => write!(f, "{}:{}: {}", location.line, location.column, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Why a script did not run to completion, by the step that rejected it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Lexical(Diagnostic),
+    Syntax(Vec<Diagnostic>),
+    /// Mistakes the resolver finds before anything runs, such as reading a
+    /// local in its own initializer
+    Resolution(Vec<Diagnostic>),
+    Runtime(Diagnostic),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_points_at_column() {
+        let source = "var a = 1;\nprint a +;\n";
+        let diagnostic = Diagnostic::at("Expect expression, found ';'.", 2, 10);
+        assert_eq!(
+            diagnostic.render("script.ry", source),
+            "script.ry:2:10: Expect expression, found ';'.\n 2 | print a +;\n   |          ^"
+        );
+    }
+
+    #[test]
+    fn test_render_call_stack() {
+        let source = "fun f() {\n    return 1 / 0;\n}\nf();\n";
+        let mut diagnostic = Diagnostic::at("Division by zero.", 2, 14);
+        diagnostic.call_stack = vec![CallFrame {
+            function: "f".to_string(),
+            line: 4,
+        }];
+        assert_eq!(
+            diagnostic.render("script.ry", source),
+            "script.ry:2:14: Division by zero.\n 2 |     return 1 / 0;\n   |              ^\n\
+             Call stack (most recent call first):\n  in f() called from script.ry:4"
+        );
+    }
+
+    #[test]
+    fn test_render_collapses_repeated_frames() {
+        let mut diagnostic = Diagnostic::new("Maximum recursion depth exceeded.", None);
+        let frame = CallFrame {
+            function: "f".to_string(),
+            line: 1,
+        };
+        diagnostic.call_stack = vec![frame; 10];
+        assert_eq!(
+            diagnostic.render("script.ry", ""),
+            concat!(
+                "script.ry: Maximum recursion depth exceeded.\n",
+                "Call stack (most recent call first):\n",
+                "  in f() called from script.ry:1\n",
+                "  in f() called from script.ry:1\n",
+                "  in f() called from script.ry:1\n",
+                "  [previous frame repeated 7 more times]",
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_without_location() {
+        let diagnostic = Diagnostic::new("Something failed.", None);
+        assert_eq!(diagnostic.render("script.ry", ""), "script.ry: Something failed.");
+    }
+}
@@ -1,9 +1,15 @@
-use crate::ast_parser::{Expr, Literal, Stmt};
+use crate::ast_parser::{Binding, Expr, Literal, Stmt};
+use crate::compiler::{Chunk, Compiler};
+use crate::error::{CallFrame, Diagnostic, Location};
 use crate::lexer::{Token, TokenType};
-use crate::stdlib::StdLib;
+use crate::stdlib::{array, StdLib};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
+mod vm;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
@@ -11,9 +17,12 @@ pub enum Value {
     Boolean(bool),
     Function(Rc<Function>),
     NativeFunction(Rc<NativeFunction>),
-    Array(Vec<Value>),
-    Object(HashMap<String, Value>),
+    // Arrays and objects are shared by reference, like in JavaScript
+    Array(Rc<RefCell<Vec<Value>>>),
+    Object(Rc<RefCell<HashMap<String, Value>>>),
     Namespace(String, HashMap<String, Value>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
     Nil,
 }
 
@@ -21,14 +30,51 @@ pub enum Value {
 pub struct Function {
     pub name: Token,
     pub params: Vec<Token>,
-    pub body: Vec<Stmt>,
+    pub body: Body,
+    /// The environment the function was declared in, captured so the body
+    /// can keep referring to the locals of its enclosing scopes.
+    pub closure: Rc<RefCell<Environment>>,
+    /// Set for a class's `init` method, which always returns `this`
+    pub is_initializer: bool,
 }
 
+/// The code a function runs when called
 #[derive(Debug, Clone)]
+pub enum Body {
+    /// Statements, for functions created by the tree-walking interpreter
+    Tree(Rc<Vec<Stmt>>),
+    /// Bytecode, for functions created by the bytecode backend
+    Compiled(Rc<Chunk>),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub superclass: Option<Rc<Class>>,
+    pub methods: HashMap<String, Rc<Function>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: HashMap<String, Value>,
+}
+
+/// The Rust code behind a native function. It may capture state, like the
+/// namespace an accessor such as `Math()` returns.
+pub type NativeFn = Rc<dyn Fn(Vec<Value>) -> Result<Value, String>>;
+
+#[derive(Clone)]
 pub struct NativeFunction {
     pub name: String,
     pub arity: usize,
-    pub function: fn(Vec<Value>) -> Result<Value, String>,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction").field("name", &self.name).field("arity", &self.arity).finish()
+    }
 }
 
 impl PartialEq for Function {
@@ -46,22 +92,75 @@ impl PartialEq for NativeFunction {
     }
 }
 
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        // Classes compare by identity
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        // Instances compare by identity, like objects in JavaScript
+        std::ptr::eq(self, other)
+    }
+}
+
 impl Function {
-    pub fn new(name: String, params: Vec<Token>, body: Vec<Stmt>) -> Rc<Self> {
+    pub fn new(
+        name: String,
+        params: Vec<Token>,
+        body: Vec<Stmt>,
+        closure: Rc<RefCell<Environment>>,
+    ) -> Rc<Self> {
         Rc::new(Function {
             name: Token::new(TokenType::Identifier(name.clone()), name, None, 0, 0),
             params,
-            body,
+            body: Body::Tree(Rc::new(body)),
+            closure,
+            is_initializer: false,
+        })
+    }
+
+    /// Create a copy of this method whose scope has `this` bound to `instance`
+    pub fn bind(&self, instance: Value) -> Rc<Function> {
+        let mut environment = Environment::new(Some(Rc::clone(&self.closure)));
+        environment.define("this".to_string(), instance);
+        Rc::new(Function {
+            name: self.name.clone(),
+            params: self.params.clone(),
+            body: self.body.clone(),
+            closure: Rc::new(RefCell::new(environment)),
+            is_initializer: self.is_initializer,
         })
     }
 }
 
+impl Class {
+    /// Look up a method on this class, falling back to its superclasses
+    pub fn find_method(&self, name: &str) -> Option<Rc<Function>> {
+        match self.methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref().and_then(|superclass| superclass.find_method(name)),
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
 impl NativeFunction {
-    pub fn new(name: String, arity: usize, function: fn(Vec<Value>) -> Result<Value, String>) -> Rc<Self> {
+    pub fn new(name: String, arity: usize, function: impl Fn(Vec<Value>) -> Result<Value, String> + 'static) -> Rc<Self> {
         Rc::new(NativeFunction {
             name,
             arity,
-            function,
+            function: Rc::new(function),
         })
     }
 }
@@ -69,92 +168,286 @@ impl NativeFunction {
 #[derive(Debug)]
 pub enum RuntimeError {
     Return(Value),
-    Error(String),
+    Break,
+    Continue,
+    /// An error raised by the interpreter, with the source position of the
+    /// innermost expression that failed once it is known
+    Error(String, Option<Location>),
+    /// A value raised by `throw` (or a native function failing) that unwinds
+    /// until a `try` statement catches it
+    Throw(Value, Option<Location>),
+    /// Raised by a `?.` access on nil to skip the rest of its chain; the
+    /// enclosing `Expr::Chain` turns it into nil
+    ShortCircuit,
+}
+
+impl RuntimeError {
+    /// Attach `location` to an error that does not have a position yet.
+    fn at(self, location: Option<Location>) -> Self {
+        match self {
+            RuntimeError::Error(message, None) => RuntimeError::Error(message, location),
+            RuntimeError::Throw(value, None) => RuntimeError::Throw(value, location),
+            other => other,
+        }
+    }
 }
 
 impl std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             RuntimeError::Return(_) => write!(f, "Return value outside of function"),
-            RuntimeError::Error(msg) => write!(f, "{}", msg),
+            RuntimeError::Break => write!(f, "Break outside of loop"),
+            RuntimeError::Continue => write!(f, "Continue outside of loop"),
+            RuntimeError::Error(msg, _) => write!(f, "{}", msg),
+            RuntimeError::Throw(value, _) => write!(f, "Uncaught exception: {:?}", value),
+            RuntimeError::ShortCircuit => write!(f, "Optional chain outside of expression"),
         }
     }
 }
 
 impl From<String> for RuntimeError {
     fn from(error: String) -> Self {
-        RuntimeError::Error(error)
+        RuntimeError::Error(error, None)
     }
 }
 
-#[derive(Clone)]
+/// One step of an assignment target such as `a.b[0]`, with any index
+/// expression already evaluated
+enum PathKey {
+    Field(String),
+    Index(Value),
+}
+
+/// A scope in the chain of scopes a piece of code runs in. The outermost,
+/// global scope keeps its variables by name; local scopes keep theirs in
+/// slots numbered by the resolver.
 pub struct Environment {
     values: HashMap<String, Value>,
-    enclosing: Option<Box<Environment>>,
+    slots: Vec<Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
 }
 
 impl Environment {
-    pub fn new(enclosing: Option<Box<Environment>>) -> Self {
+    pub fn new(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
         Environment {
             values: HashMap::new(),
+            slots: Vec::new(),
             enclosing,
         }
     }
 
+    /// Define a variable in this scope. A local takes the next slot, so locals
+    /// must be defined in the order the resolver numbered them.
     pub fn define(&mut self, name: String, value: Value) {
-        self.values.insert(name, value);
+        if self.enclosing.is_some() {
+            self.slots.push(value);
+        } else {
+            self.values.insert(name, value);
+        }
     }
 
+    /// Look up a global by name.
     pub fn get(&self, name: &str) -> Option<Value> {
         if let Some(value) = self.values.get(name) {
             Some(value.clone())
         } else if let Some(enclosing) = &self.enclosing {
-            enclosing.get(name)
+            enclosing.borrow().get(name)
         } else {
             None
         }
     }
 
     pub fn assign(&mut self, name: &str, value: Value) -> bool {
-        if self.values.contains_key(name) {
-            self.values.insert(name.to_string(), value);
+        if let Some(slot) = self.values.get_mut(name) {
+            *slot = value;
             true
-        } else if let Some(enclosing) = &mut self.enclosing {
-            enclosing.assign(name, value)
+        } else if let Some(enclosing) = &self.enclosing {
+            enclosing.borrow_mut().assign(name, value)
         } else {
             false
         }
     }
+
+    /// Read a local the resolver bound to `binding`. Gives `None` while the
+    /// local has not been defined yet.
+    pub fn get_at(&self, binding: Binding) -> Option<Value> {
+        if binding.depth == 0 {
+            return self.slots.get(binding.slot).cloned();
+        }
+        self.enclosing.as_ref()?.borrow().get_at(Binding { depth: binding.depth - 1, ..binding })
+    }
+
+    pub fn assign_at(&mut self, binding: Binding, value: Value) -> bool {
+        if binding.depth == 0 {
+            return match self.slots.get_mut(binding.slot) {
+                Some(slot) => {
+                    *slot = value;
+                    true
+                }
+                None => false,
+            };
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow_mut().assign_at(Binding { depth: binding.depth - 1, ..binding }, value),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Functions capture the environment they are declared in, which in turn
+        // holds the function, so only the names are printed to avoid recursing.
+        let mut names: Vec<&String> = self.values.keys().collect();
+        names.sort();
+        f.debug_struct("Environment")
+            .field("names", &names)
+            .field("slots", &self.slots.len())
+            .field("has_enclosing", &self.enclosing.is_some())
+            .finish()
+    }
+}
+
+/// Default limit on nested function calls, see `Interpreter::set_max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// The evaluator recurses on the native stack; when less than `STACK_RED_ZONE`
+// bytes are left, execution continues on a freshly allocated segment.
+const STACK_RED_ZONE: usize = 256 * 1024;
+const STACK_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// How `Interpreter::interpret` runs a program.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Evaluate the syntax tree directly
+    #[default]
+    TreeWalker,
+    /// Compile to bytecode and run it on a stack machine
+    Bytecode,
 }
 
 pub struct Interpreter {
-    environment: Environment,
+    backend: Backend,
+    // The global scope, where variables the resolver left unbound live
+    globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    // Calls nested deeper than this raise a runtime error
+    max_call_depth: usize,
+    // Functions currently being called, outermost first
+    call_stack: Vec<CallFrame>,
+    // Snapshot of `call_stack` taken when the error now unwinding was raised
+    traceback: Vec<CallFrame>,
+    // Operands of the bytecode being run, shared by all active calls
+    stack: Vec<Value>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let mut interpreter = Interpreter {
-            environment: Environment::new(None),
-        };
+        Self::with_stdlib(StdLib::new())
+    }
+
+    /// Create an interpreter whose globals are the functions and namespaces
+    /// of `stdlib`, e.g. one built without the `File` namespace.
+    pub fn with_stdlib(stdlib: StdLib) -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new(None)));
 
-        // Initialize standard library
-        let stdlib = StdLib::new();
         for (name, function) in stdlib.get_functions() {
-            interpreter.environment.define(name.clone(), Value::NativeFunction(function.clone()));
+            globals.borrow_mut().define(name.clone(), Value::NativeFunction(function.clone()));
         }
 
-        interpreter
+        Interpreter {
+            backend: Backend::default(),
+            environment: Rc::clone(&globals),
+            globals,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            call_stack: Vec::new(),
+            traceback: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Choose how programs are run. Both backends share the globals defined
+    /// so far and produce the same output.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Limit how deeply function calls may nest. A call beyond the limit raises
+    /// a catchable "Maximum recursion depth exceeded." error.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
     }
 
-    pub fn interpret(&mut self, statements: Vec<Stmt>) -> Result<(), String> {
-        for statement in statements {
-            match self.execute(&statement) {
-                Ok(_) => {},
-                Err(RuntimeError::Return(_)) => return Err("Return statement outside of function".to_string()),
-                Err(RuntimeError::Error(msg)) => return Err(msg),
+    pub fn interpret(&mut self, statements: Vec<Stmt>) -> Result<(), Diagnostic> {
+        match self.backend {
+            Backend::TreeWalker => {
+                for statement in statements {
+                    self.execute(&statement).or_else(|error| self.report(error))?;
+                }
+                Ok(())
+            }
+            Backend::Bytecode => {
+                let chunk = Compiler::new().compile(&statements);
+                let globals = Rc::clone(&self.globals);
+                self.run_chunk(&chunk, globals).map(|_| ()).or_else(|error| self.report(error))
             }
         }
-        Ok(())
+    }
+
+    /// Turn an error that reached the top level into a diagnostic.
+    fn report(&mut self, error: RuntimeError) -> Result<(), Diagnostic> {
+        match error {
+            // A short-circuit never escapes the chain that raised it, so one
+            // reaching the top level is a bug in the interpreter
+            RuntimeError::ShortCircuit => {
+                Err(Diagnostic::new("Internal error: optional chain short-circuit escaped its expression", None))
+            }
+            RuntimeError::Return(_) => Err(Diagnostic::new("Return statement outside of function", None)),
+            RuntimeError::Break => Err(Diagnostic::new("Break statement outside of loop", None)),
+            RuntimeError::Continue => Err(Diagnostic::new("Continue statement outside of loop", None)),
+            RuntimeError::Error(msg, location) => Err(self.with_traceback(Diagnostic::new(msg, location))),
+            RuntimeError::Throw(value, location) => {
+                let message = self.describe_uncaught(value);
+                Err(self.with_traceback(Diagnostic::new(message, location)))
+            }
+        }
+    }
+
+    /// Attach the call stack recorded for the error that reached the top level.
+    fn with_traceback(&mut self, mut diagnostic: Diagnostic) -> Diagnostic {
+        diagnostic.call_stack = std::mem::take(&mut self.traceback);
+        diagnostic
+    }
+
+    /// Describe an exception that reached the top level. Error objects show
+    /// their kind and message; any other thrown value is stringified.
+    fn describe_uncaught(&self, value: Value) -> String {
+        if let (Some(Value::String(kind)), Some(Value::String(message))) =
+            (value.get_property("kind"), value.get_property("message"))
+        {
+            return format!("{}: {}", kind, message);
+        }
+        format!("Uncaught exception: {}", self.stringify(value))
+    }
+
+    /// Build the `{ kind, message }` object a `catch` clause receives for
+    /// errors raised by the interpreter or by native functions.
+    fn error_object(kind: &str, message: String) -> Value {
+        let mut fields = HashMap::new();
+        fields.insert("kind".to_string(), Value::String(kind.to_string()));
+        fields.insert("message".to_string(), Value::String(message));
+        Value::object(fields)
+    }
+
+    /// Wrap an error returned by a native function so scripts can catch it.
+    fn native_error(message: String) -> RuntimeError {
+        RuntimeError::Throw(Self::error_object("NativeError", message), None)
     }
 
     fn execute(&mut self, stmt: &Stmt) -> Result<(), RuntimeError> {
@@ -174,25 +467,12 @@ impl Interpreter {
                 } else {
                     Value::Nil
                 };
-                self.environment.define(name.lexeme.clone(), value);
+                self.environment.borrow_mut().define(name.lexeme.clone(), value);
                 Ok(())
             }
             Stmt::Block(statements) => {
-                let previous = self.environment.clone();
-                self.environment = Environment::new(Some(Box::new(previous)));
-
-                for stmt in statements {
-                    match self.execute(stmt) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            self.environment = *self.environment.enclosing.take().unwrap();
-                            return Err(e);
-                        }
-                    }
-                }
-
-                self.environment = *self.environment.enclosing.take().unwrap();
-                Ok(())
+                let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                self.execute_block(statements, Rc::new(RefCell::new(environment)))
             }
             Stmt::If(condition, then_branch, else_branch) => {
                 let condition_value = self.evaluate(condition)?;
@@ -210,16 +490,105 @@ impl Interpreter {
                         break;
                     }
                     match self.execute(body) {
-                        Ok(_) => {},
-                        Err(RuntimeError::Return(value)) => return Err(RuntimeError::Return(value)),
+                        Ok(_) | Err(RuntimeError::Continue) => {},
+                        Err(RuntimeError::Break) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                // The loop gets its own scope so the initializer variable does
+                // not leak into the surrounding code
+                let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                self.with_environment(Rc::new(RefCell::new(environment)), |interpreter| {
+                    if let Some(initializer) = initializer {
+                        interpreter.execute(initializer)?;
+                    }
+                    loop {
+                        if let Some(condition) = condition {
+                            let condition_value = interpreter.evaluate(condition)?;
+                            if !interpreter.is_truthy(&condition_value) {
+                                break;
+                            }
+                        }
+                        match interpreter.execute(body) {
+                            Ok(_) | Err(RuntimeError::Continue) => {},
+                            Err(RuntimeError::Break) => break,
+                            Err(e) => return Err(e),
+                        }
+                        if let Some(increment) = increment {
+                            interpreter.evaluate(increment)?;
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            Stmt::ForIn(index, item, iterable_expr, body) => {
+                let iterable = self.evaluate(iterable_expr)?;
+                let pairs = self
+                    .for_in_pairs(&iterable, index.is_some())
+                    .map_err(|error| error.at(iterable_expr.location()))?;
+                for (position, element) in pairs {
+                    // Every iteration gets a fresh scope so closures created in
+                    // the body capture that iteration's values
+                    let mut environment = Environment::new(Some(Rc::clone(&self.environment)));
+                    if let Some(index) = index {
+                        environment.define(index.lexeme.clone(), position);
+                    }
+                    environment.define(item.lexeme.clone(), element);
+                    let result = self.with_environment(Rc::new(RefCell::new(environment)), |interpreter| {
+                        interpreter.execute(body)
+                    });
+                    match result {
+                        Ok(_) | Err(RuntimeError::Continue) => {},
+                        Err(RuntimeError::Break) => break,
                         Err(e) => return Err(e),
                     }
                 }
                 Ok(())
             }
             Stmt::Function(name, params, body) => {
-                let function = Function::new(name.lexeme.clone(), params.clone(), body.clone());
-                self.environment.define(name.lexeme.clone(), Value::Function(function));
+                let function = Function::new(
+                    name.lexeme.clone(),
+                    params.clone(),
+                    body.clone(),
+                    Rc::clone(&self.environment),
+                );
+                self.environment.borrow_mut().define(name.lexeme.clone(), Value::Function(function));
+                Ok(())
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let superclass = match superclass {
+                    Some(expr) => match self.evaluate(expr)? {
+                        Value::Class(class) => Some(class),
+                        _ => return Err(RuntimeError::Error("Superclass must be a class.".to_string(), expr.location())),
+                    },
+                    None => None,
+                };
+
+                let method_closure = self.method_closure(&superclass);
+
+                let mut class_methods = HashMap::new();
+                for method in methods {
+                    if let Stmt::Function(method_name, params, body) = method {
+                        let function = Function {
+                            name: method_name.clone(),
+                            params: params.clone(),
+                            body: Body::Tree(Rc::new(body.clone())),
+                            closure: Rc::clone(&method_closure),
+                            is_initializer: method_name.lexeme == "init",
+                        };
+                        class_methods.insert(method_name.lexeme.clone(), Rc::new(function));
+                    }
+                }
+
+                let class = Class {
+                    name: name.lexeme.clone(),
+                    superclass,
+                    methods: class_methods,
+                };
+                self.environment.borrow_mut().define(name.lexeme.clone(), Value::Class(Rc::new(class)));
                 Ok(())
             }
             Stmt::Return(_keyword, value) => {
@@ -230,10 +599,259 @@ impl Interpreter {
                 };
                 Err(RuntimeError::Return(return_value))
             }
+            Stmt::Break(_) => Err(RuntimeError::Break),
+            Stmt::Continue(_) => Err(RuntimeError::Continue),
+            Stmt::Throw(keyword, value) => {
+                let value = self.evaluate(value)?;
+                Err(RuntimeError::Throw(value, Some(keyword.location())))
+            }
+            Stmt::Try(body, catch, finally) => {
+                let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                let mut result = self.execute_block(body, Rc::new(RefCell::new(environment)));
+
+                if let Some((name, handler)) = catch {
+                    // Only errors are caught; return, break and continue pass through
+                    if let Err(error) = result {
+                        result = match Self::exception_value(error) {
+                            Ok(exception) => self.execute_catch(name, handler, exception),
+                            Err(error) => Err(error),
+                        };
+                    }
+                }
+
+                if let Some(finally) = finally {
+                    // A finally block that itself exits early overrides the pending
+                    // outcome, and drops the traceback of a pending error with it
+                    let traceback = std::mem::take(&mut self.traceback);
+                    let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                    self.execute_block(finally, Rc::new(RefCell::new(environment)))?;
+                    self.traceback = traceback;
+                }
+                result
+            }
+        }
+    }
+
+    /// The scope the methods of a class close over. Methods of a subclass
+    /// close over one that binds `super`.
+    fn method_closure(&self, superclass: &Option<Rc<Class>>) -> Rc<RefCell<Environment>> {
+        match superclass {
+            Some(superclass) => {
+                let mut environment = Environment::new(Some(Rc::clone(&self.environment)));
+                environment.define("super".to_string(), Value::Class(Rc::clone(superclass)));
+                Rc::new(RefCell::new(environment))
+            }
+            None => Rc::clone(&self.environment),
+        }
+    }
+
+    /// The value a `catch` clause receives for `error`, or the error itself
+    /// when it is not an exception, like `return`, `break` and `continue`.
+    fn exception_value(error: RuntimeError) -> Result<Value, RuntimeError> {
+        match error {
+            RuntimeError::Throw(value, _) => Ok(value),
+            RuntimeError::Error(message, _) => Ok(Self::error_object("RuntimeError", message)),
+            other => Err(other),
+        }
+    }
+
+    /// Run a `catch` clause with its variable bound to `exception`.
+    fn execute_catch(&mut self, name: &Token, handler: &[Stmt], exception: Value) -> Result<(), RuntimeError> {
+        // The error was handled, so its traceback is no longer needed
+        self.traceback.clear();
+        let mut environment = Environment::new(Some(Rc::clone(&self.environment)));
+        environment.define(name.lexeme.clone(), exception);
+        self.execute_block(handler, Rc::new(RefCell::new(environment)))
+    }
+
+    /// Produce the values a for-in loop binds on each iteration. A single loop
+    /// variable walks the keys of an object but the elements of an array or
+    /// string.
+    fn for_in_pairs(&self, iterable: &Value, with_index: bool) -> Result<Vec<(Value, Value)>, RuntimeError> {
+        let keys_only = !with_index && matches!(iterable, Value::Object(_) | Value::Namespace(_, _));
+        let pairs = self.iteration_pairs(iterable)?;
+        if keys_only {
+            return Ok(pairs.into_iter().map(|(key, _)| (key.clone(), key)).collect());
+        }
+        Ok(pairs)
+    }
+
+    /// Produce the (index, item) pairs a `for (index, item in ...)` loop visits:
+    /// (position, element) for arrays, (position, character) for strings and
+    /// (key, value) for objects, in sorted key order.
+    fn iteration_pairs(&self, iterable: &Value) -> Result<Vec<(Value, Value)>, RuntimeError> {
+        match iterable {
+            Value::Array(elements) => Ok(elements
+                .borrow()
+                .iter()
+                .enumerate()
+                .map(|(i, element)| (Value::Number(i as f64), element.clone()))
+                .collect()),
+            Value::String(s) => Ok(s
+                .chars()
+                .enumerate()
+                .map(|(i, c)| (Value::Number(i as f64), Value::String(c.to_string())))
+                .collect()),
+            Value::Object(properties) => Ok(Self::sorted_entries(&properties.borrow())),
+            Value::Namespace(_, properties) => Ok(Self::sorted_entries(properties)),
+            _ => Err(RuntimeError::Error(
+                "Can only iterate over arrays, strings and objects.".to_string(),
+                None,
+            )),
+        }
+    }
+
+    fn sorted_entries(properties: &HashMap<String, Value>) -> Vec<(Value, Value)> {
+        let mut keys: Vec<&String> = properties.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| (Value::String(key.clone()), properties[key].clone()))
+            .collect()
+    }
+
+    /// Execute `statements` with `environment` as the current scope, restoring
+    /// the previous scope afterwards even when a statement fails or returns.
+    fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<(), RuntimeError> {
+        self.with_environment(environment, |interpreter| {
+            statements.iter().try_for_each(|stmt| interpreter.execute(stmt))
+        })
+    }
+
+    /// Run `f` with `environment` as the current scope, restoring the previous
+    /// scope afterwards whatever the outcome.
+    fn with_environment<T>(
+        &mut self,
+        environment: Rc<RefCell<Environment>>,
+        f: impl FnOnce(&mut Self) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let result = f(self);
+        self.environment = previous;
+        result
+    }
+
+    /// Call a user-defined function. The body runs in a fresh scope chained onto
+    /// the environment captured when the function was declared.
+    fn call_function(&mut self, function: &Function, args: Vec<Value>, line: usize) -> Result<Value, RuntimeError> {
+        if args.len() != function.params.len() {
+            return Err(RuntimeError::Error(
+                format!("Expected {} arguments but got {}.", function.params.len(), args.len()),
+                None,
+            ));
+        }
+
+        let mut environment = Environment::new(Some(Rc::clone(&function.closure)));
+        for (param, arg) in function.params.iter().zip(args) {
+            environment.define(param.lexeme.clone(), arg);
+        }
+
+        let environment = Rc::new(RefCell::new(environment));
+        let result = self.with_frame(&function.name.lexeme, line, |interpreter| match &function.body {
+            Body::Compiled(chunk) => interpreter.run_chunk(chunk, environment),
+            Body::Tree(body) => match interpreter.execute_block(body, environment) {
+                Ok(()) => Ok(Value::Nil),
+                Err(RuntimeError::Return(value)) => Ok(value),
+                Err(e) => Err(e),
+            },
+        })?;
+
+        if function.is_initializer {
+            // `init` hands back the instance being constructed
+            return Ok(function.closure.borrow().get_at(Binding { depth: 0, slot: 0 }).unwrap_or(Value::Nil));
         }
+        Ok(result)
+    }
+
+    /// Run `f` as a call to `function` made on `line`, keeping the frame
+    /// on the call stack meanwhile. The first frame an error unwinds through
+    /// records the whole stack so it can be reported if nothing catches it.
+    fn with_frame<T>(
+        &mut self,
+        function: &str,
+        line: usize,
+        f: impl FnOnce(&mut Self) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        if self.call_stack.len() >= self.max_call_depth {
+            return Err(RuntimeError::Error("Maximum recursion depth exceeded.".to_string(), None));
+        }
+        self.call_stack.push(CallFrame {
+            function: function.to_string(),
+            line,
+        });
+        let result = f(self);
+        if matches!(result, Err(RuntimeError::Error(..) | RuntimeError::Throw(..))) && self.traceback.is_empty() {
+            self.traceback = self.call_stack.clone();
+        }
+        self.call_stack.pop();
+        result
+    }
+
+    /// Call any callable value: user functions, native functions and classes.
+    fn call_value(&mut self, callee: &Value, args: Vec<Value>, line: usize) -> Result<Value, RuntimeError> {
+        match callee {
+            Value::Function(function) => self.call_function(function, args, line),
+            Value::NativeFunction(function) => self.call_native(function, args, line),
+            Value::Class(class) => self.instantiate(class, args, line),
+            _ => Err(RuntimeError::Error("Can only call functions and classes.".to_string(), None)),
+        }
+    }
+
+    /// Construct a new instance of `class`, running its `init` method if it has one.
+    fn instantiate(&mut self, class: &Rc<Class>, args: Vec<Value>, line: usize) -> Result<Value, RuntimeError> {
+        let instance = Value::Instance(Rc::new(RefCell::new(Instance::new(Rc::clone(class)))));
+
+        if let Some(initializer) = class.find_method("init") {
+            self.call_function(&initializer.bind(instance.clone()), args, line)?;
+        } else if !args.is_empty() {
+            return Err(RuntimeError::Error(
+                format!("Expected 0 arguments but got {}.", args.len()),
+                None,
+            ));
+        }
+
+        Ok(instance)
+    }
+
+    /// Read a field of an instance, or bind one of its class's methods to it.
+    fn instance_get(&self, instance: &Rc<RefCell<Instance>>, name: &str) -> Result<Value, RuntimeError> {
+        if let Some(value) = instance.borrow().fields.get(name) {
+            return Ok(value.clone());
+        }
+
+        let method = instance.borrow().class.find_method(name);
+        match method {
+            Some(method) => Ok(Value::Function(method.bind(Value::Instance(Rc::clone(instance))))),
+            None => Err(RuntimeError::Error(format!("Undefined property '{}'.", name), None)),
+        }
+    }
+
+    /// Call a native function, checking its arity first.
+    fn call_native(&mut self, function: &NativeFunction, args: Vec<Value>, line: usize) -> Result<Value, RuntimeError> {
+        // Special handling for Array() constructor to allow variable arguments
+        let is_array_constructor = function.name == "Array";
+
+        // Only check arity if it's not the Array constructor and arity is not 0
+        if !is_array_constructor && function.arity != 0 && args.len() != function.arity {
+            return Err(RuntimeError::Error(
+                format!("Expected {} arguments but got {}.", function.arity, args.len()),
+                None,
+            ));
+        }
+
+        self.with_frame(&function.name, line, |_| (function.function)(args).map_err(Self::native_error))
     }
 
     fn evaluate(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || {
+            self.evaluate_expr(expr).map_err(|error| error.at(expr.location()))
+        })
+    }
+
+    fn evaluate_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
         match expr {
             Expr::Literal(literal) => Ok(match literal {
                 Literal::Number(n) => Value::Number(*n),
@@ -249,92 +867,23 @@ impl Interpreter {
                         if let Value::Number(n) = right {
                             Ok(Value::Number(-n))
                         } else {
-                            Err(RuntimeError::Error("Operand must be a number.".to_string()))
+                            Err(RuntimeError::Error("Operand must be a number.".to_string(), None))
                         }
                     }
                     TokenType::Bang => Ok(Value::Boolean(!self.is_truthy(&right))),
-                    _ => Err(RuntimeError::Error("Invalid unary operator.".to_string())),
+                    _ => Err(RuntimeError::Error("Invalid unary operator.".to_string(), None)),
                 }
             }
             Expr::Binary(left, operator, right) => {
                 let left = self.evaluate(left)?;
                 let right = self.evaluate(right)?;
-
-                match operator.token_type {
-                    TokenType::Minus => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::Slash => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => {
-                                if b == 0.0 {
-                                    Err(RuntimeError::Error("Division by zero.".to_string()))
-                                } else {
-                                    Ok(Value::Number(a / b))
-                                }
-                            }
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::Star => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::Plus => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-                            (Value::String(a), Value::String(b)) => Ok(Value::String(a + &b)),
-                            _ => Err(RuntimeError::Error("Operands must be two numbers or two strings.".to_string())),
-                        }
-                    }
-                    TokenType::Greater => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a > b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::GreaterEqual => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a >= b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::Less => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a < b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::LessEqual => {
-                        match (left, right) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a <= b)),
-                            _ => Err(RuntimeError::Error("Operands must be numbers.".to_string())),
-                        }
-                    }
-                    TokenType::BangEqual => Ok(Value::Boolean(left != right)),
-                    TokenType::EqualEqual => Ok(Value::Boolean(left == right)),
-                    _ => Err(RuntimeError::Error("Invalid binary operator.".to_string())),
-                }
-            }
-            Expr::Variable(name) => {
-                if let Some(value) = self.environment.get(&name.lexeme) {
-                    Ok(value)
-                } else {
-                    Err(RuntimeError::Error(format!("Undefined variable '{}'.", name.lexeme)))
-                }
+                self.binary_op(&operator.token_type, left, right)
             }
-            Expr::Assign(name, value) => {
+            Expr::Variable(name, binding) => self.lookup_variable(&name.lexeme, *binding),
+            Expr::Assign(name, value, binding) => {
                 let value = self.evaluate(value)?;
-                if self.environment.assign(&name.lexeme, value.clone()) {
-                    Ok(value)
-                } else {
-                    Err(RuntimeError::Error(format!("Undefined variable '{}'.", name.lexeme)))
-                }
+                self.assign_variable(&name.lexeme, *binding, value.clone())?;
+                Ok(value)
             }
             Expr::Logical(left, operator, right) => {
                 let left = self.evaluate(left)?;
@@ -350,142 +899,69 @@ impl Interpreter {
                             return Ok(left);
                         }
                     }
-                    _ => return Err(RuntimeError::Error("Invalid logical operator.".to_string())),
+                    TokenType::QuestionQuestion => {
+                        if left != Value::Nil {
+                            return Ok(left);
+                        }
+                    }
+                    _ => return Err(RuntimeError::Error("Invalid logical operator.".to_string(), None)),
                 }
 
                 self.evaluate(right)
             }
-            Expr::Call(callee, _paren, arguments) => {
+            Expr::Conditional(condition, _question, then_branch, else_branch) => {
+                let condition = self.evaluate(condition)?;
+                if self.is_truthy(&condition) {
+                    self.evaluate(then_branch)
+                } else {
+                    self.evaluate(else_branch)
+                }
+            }
+            Expr::Optional(object) => match self.evaluate(object)? {
+                Value::Nil => Err(RuntimeError::ShortCircuit),
+                value => Ok(value),
+            },
+            Expr::Chain(chain) => match self.evaluate(chain) {
+                Err(RuntimeError::ShortCircuit) => Ok(Value::Nil),
+                result => result,
+            },
+            Expr::Call(callee, paren, arguments) => {
                 let callee_value = self.evaluate(callee)?;
                 let mut args = Vec::new();
                 for argument in arguments {
                     args.push(self.evaluate(argument)?);
                 }
 
-                if !callee_value.is_callable() {
-                    return Err(RuntimeError::Error(format!("Can only call functions and classes.")));
-                }
-
-                if let Value::Function(function) = &callee_value {
-                    if args.len() != function.params.len() {
-                        return Err(RuntimeError::Error(format!(
-                            "Expected {} arguments but got {}.",
-                            function.params.len(),
-                            args.len()
-                        )));
-                    }
-
-                    // Create a new environment for the function call
-                    let previous = self.environment.clone();
-                    self.environment = Environment::new(Some(Box::new(previous)));
-
-                    // Bind arguments to parameters
-                    for (param, arg) in function.params.iter().zip(args.iter()) {
-                        self.environment.define(param.lexeme.clone(), arg.clone());
-                    }
-
-                    // Execute function body
-                    let mut return_value = Value::Nil;
-                    for stmt in &function.body {
-                        match self.execute(stmt) {
-                            Ok(_) => {},
-                            Err(RuntimeError::Return(value)) => {
-                                return_value = value;
-                                break;
-                            },
-                            Err(e) => {
-                                // Restore environment and propagate error
-                                self.environment = *self.environment.enclosing.take().unwrap();
-                                return Err(e);
-                            }
-                        }
-                    }
-
-                    // Restore environment
-                    self.environment = *self.environment.enclosing.take().unwrap();
-                    Ok(return_value)
-                } else if let Value::NativeFunction(function) = &callee_value {
-                    // Special handling for Array() constructor to allow variable arguments
-                    let is_array_constructor = function.name == "Array";
-
-                    // Only check arity if it's not the Array constructor and arity is not 0
-                    if !is_array_constructor && function.arity != 0 && args.len() != function.arity {
-                        return Err(RuntimeError::Error(format!(
-                            "Expected {} arguments but got {}.",
-                            function.arity,
-                            args.len()
-                        )));
-                    }
-
-                    match (function.function)(args) {
-                        Ok(value) => Ok(value),
-                        Err(message) => Err(RuntimeError::Error(message)),
-                    }
-                } else {
-                    Err(RuntimeError::Error("Can only call functions and classes.".to_string()))
-                }
+                self.call_value(&callee_value, args, paren.line)
             },
             Expr::Get(object, name) => {
                 // Special case for namespace.method static method access (when used without immediate call)
-                if let Expr::Variable(var_name) = &**object {
+                if let Expr::Variable(var_name, None) = &**object {
                     // Support any namespace (Array, String, Math, etc.)
                     let method_name = format!("{}.{}", var_name.lexeme, name.lexeme);
-                    if let Some(method) = self.environment.get(&method_name) {
+                    let method = self.globals.borrow().get(&method_name);
+                    if let Some(method) = method {
                         return Ok(method);
                     }
                 }
 
-                // Regular property access
                 let object_value = self.evaluate(object)?;
-
-                // Handle property access for different types
-                match &object_value {
-                    Value::Object(_) | Value::Namespace(_, _) => {
-                        if let Some(property) = object_value.get_property(&name.lexeme) {
-                            Ok(property)
-                        } else {
-                            Err(RuntimeError::Error(format!("Property '{}' not found.", name.lexeme)))
-                        }
-                    },
-                    Value::Array(elements) => {
-                        match name.lexeme.as_str() {
-                            "length" => Ok(Value::Number(elements.len() as f64)),
-                            _ => Err(RuntimeError::Error(format!("Array has no property '{}'.", name.lexeme)))
-                        }
-                    },
-                    Value::String(s) => {
-                        match name.lexeme.as_str() {
-                            "length" => Ok(Value::Number(s.len() as f64)),
-                            _ => Err(RuntimeError::Error(format!("String has no property '{}'.", name.lexeme)))
-                        }
-                    },
-                    _ => Err(RuntimeError::Error(format!("Cannot access properties of non-object value.")))
-                }
+                self.get_member(&object_value, &name.lexeme)
             },
             Expr::Method(object, name, arguments) => {
                 // Special case for namespace.method() static method calls
-                if let Expr::Variable(var_name) = &**object {
+                if let Expr::Variable(var_name, None) = &**object {
                     // Support any namespace (Array, String, Math, etc.)
                     let method_name = format!("{}.{}", var_name.lexeme, name.lexeme);
-                    if let Some(method) = self.environment.get(&method_name) {
+                    let method = self.globals.borrow().get(&method_name);
+                    if let Some(method) = method {
                         let mut args = Vec::new();
                         for argument in arguments {
                             args.push(self.evaluate(argument)?);
                         }
 
                         if let Value::NativeFunction(function) = &method {
-                            if function.arity != 0 && args.len() != function.arity {
-                                return Err(RuntimeError::Error(format!(
-                                    "Expected {} arguments but got {}.",
-                                    function.arity,
-                                    args.len()
-                                )));
-                            }
-
-                            match (function.function)(args) {
-                                Ok(value) => return Ok(value),
-                                Err(message) => return Err(RuntimeError::Error(message)),
-                            }
+                            return self.call_native(function, args, name.line);
                         }
                     }
                 }
@@ -497,91 +973,437 @@ impl Interpreter {
                     args.push(self.evaluate(argument)?);
                 }
 
-                // Handle method calls for different types
-                match &object_value {
-                    Value::Object(_) | Value::Namespace(_, _) => {
-                        let name_str = &name.lexeme;
-                        if let Some(method) = object_value.get_property(name_str) {
-                            if method.is_callable() {
-                                if let Value::NativeFunction(function) = &method {
-                                    // Special handling for Array() constructor to allow variable arguments
-                                    let is_array_constructor = function.name == "Array";
-
-                                    // Only check arity if it's not the Array constructor and arity is not 0
-                                    if !is_array_constructor && function.arity != 0 && args.len() != function.arity {
-                                        return Err(RuntimeError::Error(format!(
-                                            "Expected {} arguments but got {}.",
-                                            function.arity,
-                                            args.len()
-                                        )));
-                                    }
-
-                                    match (function.function)(args) {
-                                        Ok(value) => Ok(value),
-                                        Err(message) => Err(RuntimeError::Error(message)),
-                                    }
-                                } else if let Value::Function(function) = &method {
-                                    // Similar implementation as for regular function calls
-                                    if args.len() != function.params.len() {
-                                        return Err(RuntimeError::Error(format!(
-                                            "Expected {} arguments but got {}.",
-                                            function.params.len(),
-                                            args.len()
-                                        )));
-                                    }
-
-                                    // Create a new environment for the function call
-                                    let previous = self.environment.clone();
-                                    self.environment = Environment::new(Some(Box::new(previous)));
-
-                                    // Bind arguments to parameters
-                                    for (param, arg) in function.params.iter().zip(args.iter()) {
-                                        self.environment.define(param.lexeme.clone(), arg.clone());
-                                    }
-
-                                    // Execute function body
-                                    let mut return_value = Value::Nil;
-                                    for stmt in &function.body {
-                                        match self.execute(stmt) {
-                                            Ok(_) => {},
-                                            Err(RuntimeError::Return(value)) => {
-                                                return_value = value;
-                                                break;
-                                            },
-                                            Err(e) => {
-                                                // Restore environment and propagate error
-                                                self.environment = *self.environment.enclosing.take().unwrap();
-                                                return Err(e);
-                                            }
-                                        }
-                                    }
-
-                                    // Restore environment
-                                    self.environment = *self.environment.enclosing.take().unwrap();
-                                    Ok(return_value)
-                                } else {
-                                    Err(RuntimeError::Error("Method is not callable.".to_string()))
-                                }
-                            } else {
-                                Err(RuntimeError::Error(format!("Property '{}' is not a method.", name.lexeme)))
-                            }
-                        } else {
-                            Err(RuntimeError::Error(format!("Method '{}' not found.", name.lexeme)))
-                        }
-                    },
-                    _ => Err(RuntimeError::Error(format!("Cannot call methods on non-object value.")))
-                }
+                self.invoke(object_value, &name.lexeme, args, name.line)
             },
             Expr::Array(elements) => {
                 let mut values = Vec::new();
                 for element in elements {
                     values.push(self.evaluate(element)?);
                 }
-                Ok(Value::Array(values))
+                Ok(Value::array(values))
             },
-        }
-    }
-
+            Expr::Function(keyword, params, body) => {
+                let name = Token::new(
+                    TokenType::Identifier("anonymous".to_string()),
+                    "anonymous".to_string(),
+                    None,
+                    keyword.line,
+                    keyword.column,
+                );
+                Ok(Value::Function(Rc::new(Function {
+                    name,
+                    params: params.clone(),
+                    body: Body::Tree(Rc::clone(body)),
+                    closure: Rc::clone(&self.environment),
+                    is_initializer: false,
+                })))
+            },
+            Expr::Template(parts) => {
+                let mut text = String::new();
+                for part in parts {
+                    let value = self.evaluate(part)?;
+                    text.push_str(&self.stringify(value));
+                }
+                Ok(Value::String(text))
+            },
+            Expr::Object(properties) => {
+                let mut object = HashMap::new();
+                for (key, value) in properties {
+                    let value = self.evaluate(value)?;
+                    object.insert(key.clone(), value);
+                }
+                Ok(Value::object(object))
+            },
+            Expr::Index(object, _bracket, index) => {
+                let object_value = self.evaluate(object)?;
+                let index_value = self.evaluate(index)?;
+                self.get_index(object_value, index_value)
+            },
+            Expr::IndexSet(object, _bracket, index, value) => {
+                // Evaluate the target's base, then its indexes, then the value,
+                // and store the value through the path
+                let mut keys = Vec::new();
+                let (base, root) = self.resolve_path(object, &mut keys)?;
+                keys.push(PathKey::Index(self.evaluate(index)?));
+                let value = self.evaluate(value)?;
+                self.store_path(base, root, &keys, value.clone())?;
+                Ok(value)
+            },
+            Expr::Set(object, name, value) => {
+                // The object is evaluated before the value, as for `IndexSet`
+                let mut keys = Vec::new();
+                let (base, root) = self.resolve_path(object, &mut keys)?;
+                keys.push(PathKey::Field(name.lexeme.clone()));
+                let value = self.evaluate(value)?;
+                self.store_path(base, root, &keys, value.clone())?;
+                Ok(value)
+            },
+            Expr::Compound(target, operator, value) => {
+                let operator = match operator.token_type {
+                    TokenType::PlusEqual => TokenType::Plus,
+                    TokenType::MinusEqual => TokenType::Minus,
+                    TokenType::StarEqual => TokenType::Star,
+                    TokenType::SlashEqual => TokenType::Slash,
+                    TokenType::PercentEqual => TokenType::Percent,
+                    _ => return Err(RuntimeError::Error("Invalid assignment operator.".to_string(), None)),
+                };
+                let (_, updated) = self.update_target(target, |interpreter, current| {
+                    let value = interpreter.evaluate(value)?;
+                    interpreter.binary_op(&operator, current, value)
+                })?;
+                Ok(updated)
+            },
+            Expr::Update(target, operator, prefix) => {
+                let step = if operator.token_type == TokenType::PlusPlus { 1.0 } else { -1.0 };
+                let (old, updated) = self.update_target(target, |_, current| match current {
+                    Value::Number(n) => Ok(Value::Number(n + step)),
+                    _ => Err(RuntimeError::Error(
+                        format!("Operand of '{}' must be a number.", operator.lexeme),
+                        None,
+                    )),
+                })?;
+                Ok(if *prefix { updated } else { old })
+            },
+            Expr::This(_keyword, binding) => {
+                let this = binding.and_then(|binding| self.environment.borrow().get_at(binding));
+                this.ok_or_else(|| RuntimeError::Error("Can't use 'this' outside of a class method.".to_string(), None))
+            },
+            Expr::Super(_keyword, method, binding) => {
+                // Methods bind `this` in the scope just inside the one binding `super`
+                let environment = self.environment.borrow();
+                let superclass = binding.and_then(|binding| environment.get_at(binding));
+                let this = binding.and_then(|binding| {
+                    environment.get_at(Binding { depth: binding.depth.checked_sub(1)?, slot: 0 })
+                });
+                drop(environment);
+                match (superclass, this) {
+                    (Some(Value::Class(superclass)), Some(instance)) => match superclass.find_method(&method.lexeme) {
+                        Some(found) => Ok(Value::Function(found.bind(instance))),
+                        None => Err(RuntimeError::Error(format!("Undefined property '{}'.", method.lexeme), None)),
+                    },
+                    _ => Err(RuntimeError::Error("Can't use 'super' outside of a subclass method.".to_string(), None)),
+                }
+            },
+        }
+    }
+
+    /// Read the property `name` of an object, namespace, instance, array or string.
+    fn get_member(&self, object_value: &Value, name: &str) -> Result<Value, RuntimeError> {
+        match object_value {
+            Value::Object(_) | Value::Namespace(_, _) => {
+                if let Some(property) = object_value.get_property(name) {
+                    Ok(property)
+                } else {
+                    Err(RuntimeError::Error(format!("Property '{}' not found.", name), None))
+                }
+            },
+            Value::Instance(instance) => self.instance_get(instance, name),
+            Value::Array(elements) => {
+                match name {
+                    "length" => Ok(Value::Number(elements.borrow().len() as f64)),
+                    _ => Err(RuntimeError::Error(format!("Array has no property '{}'.", name), None))
+                }
+            },
+            Value::String(s) => {
+                match name {
+                    "length" => Ok(Value::Number(s.len() as f64)),
+                    _ => Err(RuntimeError::Error(format!("String has no property '{}'.", name), None))
+                }
+            },
+            _ => Err(RuntimeError::Error("Cannot access properties of non-object value.".to_string(), None))
+        }
+    }
+
+    /// Call the method `name` of `object_value` with `args`.
+    fn invoke(&mut self, object_value: Value, name: &str, mut args: Vec<Value>, line: usize) -> Result<Value, RuntimeError> {
+        match &object_value {
+            Value::Object(_) | Value::Namespace(_, _) => {
+                if let Some(method) = object_value.get_property(name) {
+                    match &method {
+                        Value::NativeFunction(function) => self.call_native(function, args, line),
+                        Value::Function(function) => self.call_function(function, args, line),
+                        _ => Err(RuntimeError::Error(format!("Property '{}' is not a method.", name), None)),
+                    }
+                } else if matches!(object_value, Value::Object(_)) && name == "clone" && args.is_empty() {
+                    Ok(object_value.deep_clone())
+                } else {
+                    Err(RuntimeError::Error(format!("Method '{}' not found.", name), None))
+                }
+            },
+            Value::Array(_) => match array::method(name) {
+                Some(function) => {
+                    args.insert(0, object_value.clone());
+                    self.call_native(&function, args, line)
+                },
+                None => Err(RuntimeError::Error(format!("Array has no method '{}'.", name), None)),
+            },
+            Value::Instance(instance) => {
+                let method = self.instance_get(instance, name)?;
+                self.call_value(&method, args, line)
+            },
+            _ => Err(RuntimeError::Error("Cannot call methods on non-object value.".to_string(), None))
+        }
+    }
+
+    /// Read a variable from the local slot the resolver bound it to, or from
+    /// the globals by name when it is not a local.
+    fn lookup_variable(&self, name: &str, binding: Option<Binding>) -> Result<Value, RuntimeError> {
+        let value = match binding {
+            Some(binding) => self.environment.borrow().get_at(binding),
+            None => self.globals.borrow().get(name),
+        };
+        value.ok_or_else(|| RuntimeError::Error(format!("Undefined variable '{}'.", name), None))
+    }
+
+    fn assign_variable(&mut self, name: &str, binding: Option<Binding>, value: Value) -> Result<(), RuntimeError> {
+        let assigned = match binding {
+            Some(binding) => self.environment.borrow_mut().assign_at(binding, value),
+            None => self.globals.borrow_mut().assign(name, value),
+        };
+        if assigned {
+            Ok(())
+        } else {
+            Err(RuntimeError::Error(format!("Undefined variable '{}'.", name), None))
+        }
+    }
+
+    /// Apply an arithmetic, comparison or equality operator to two values.
+    fn binary_op(&self, operator: &TokenType, left: Value, right: Value) -> Result<Value, RuntimeError> {
+        match operator {
+            TokenType::Minus => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::Slash => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        if b == 0.0 {
+                            Err(RuntimeError::Error("Division by zero.".to_string(), None))
+                        } else {
+                            Ok(Value::Number(a / b))
+                        }
+                    }
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::Star => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::Percent => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        if b == 0.0 {
+                            Err(RuntimeError::Error("Modulo by zero.".to_string(), None))
+                        } else {
+                            Ok(Value::Number(a % b))
+                        }
+                    }
+                    _ => Err(RuntimeError::Error("Operands of '%' must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::TildeSlash => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        if b == 0.0 {
+                            Err(RuntimeError::Error("Division by zero.".to_string(), None))
+                        } else {
+                            Ok(Value::Number((a / b).floor()))
+                        }
+                    }
+                    _ => Err(RuntimeError::Error("Operands of '~/' must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::StarStar => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a.powf(b))),
+                    _ => Err(RuntimeError::Error("Operands of '**' must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::Plus => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                    (Value::String(a), Value::String(b)) => Ok(Value::String(a + &b)),
+                    _ => Err(RuntimeError::Error("Operands must be two numbers or two strings.".to_string(), None)),
+                }
+            }
+            TokenType::Greater => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a > b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::GreaterEqual => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a >= b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::Less => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a < b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::LessEqual => {
+                match (left, right) {
+                    (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a <= b)),
+                    _ => Err(RuntimeError::Error("Operands must be numbers.".to_string(), None)),
+                }
+            }
+            TokenType::BangEqual => Ok(Value::Boolean(!Self::values_equal(&left, &right))),
+            TokenType::EqualEqual => Ok(Value::Boolean(Self::values_equal(&left, &right))),
+            _ => Err(RuntimeError::Error("Invalid binary operator.".to_string(), None)),
+        }
+    }
+
+    /// `==` compares arrays and objects by identity, like instances, and
+    /// everything else by value.
+    fn values_equal(left: &Value, right: &Value) -> bool {
+        match (left, right) {
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            _ => left == right,
+        }
+    }
+
+    /// Read `container[index]` for arrays, strings and objects.
+    fn get_index(&self, container: Value, index: Value) -> Result<Value, RuntimeError> {
+        match (&container, &index) {
+            (Value::Array(_) | Value::String(_), _) => {
+                array::index(vec![container, index]).map_err(RuntimeError::from)
+            },
+            (Value::Object(_) | Value::Namespace(_, _) | Value::Instance(_), Value::String(key)) => {
+                self.get_field(&container, key)
+            },
+            (Value::Object(_) | Value::Namespace(_, _) | Value::Instance(_), _) => {
+                Err(RuntimeError::Error("Object keys must be strings.".to_string(), None))
+            },
+            _ => Err(RuntimeError::Error("Can only index arrays, strings and objects.".to_string(), None)),
+        }
+    }
+
+    /// Return `container` with `container[index]` replaced by `value`.
+    fn set_index(&self, container: Value, index: Value, value: Value) -> Result<Value, RuntimeError> {
+        match (&container, &index) {
+            (Value::Array(_) | Value::String(_), _) => {
+                array::index_set(vec![container, index, value]).map_err(RuntimeError::from)
+            },
+            (Value::Object(_) | Value::Namespace(_, _) | Value::Instance(_), Value::String(key)) => {
+                self.set_field(container.clone(), key, value)
+            },
+            (Value::Object(_) | Value::Namespace(_, _) | Value::Instance(_), _) => {
+                Err(RuntimeError::Error("Object keys must be strings.".to_string(), None))
+            },
+            _ => Err(RuntimeError::Error("Can only index arrays, strings and objects.".to_string(), None)),
+        }
+    }
+
+    /// Read a field of an object or instance while walking an assignment path.
+    fn get_field(&self, container: &Value, name: &str) -> Result<Value, RuntimeError> {
+        let value = match container {
+            Value::Instance(instance) => instance.borrow().fields.get(name).cloned(),
+            _ => container.get_property(name),
+        };
+        value.ok_or_else(|| RuntimeError::Error(format!("Property '{}' not found.", name), None))
+    }
+
+    /// Return `container` with its field `name` set to `value`. Instances are
+    /// updated in place since they are shared by reference.
+    fn set_field(&self, mut container: Value, name: &str, value: Value) -> Result<Value, RuntimeError> {
+        if let Value::Instance(instance) = &container {
+            instance.borrow_mut().fields.insert(name.to_string(), value);
+            return Ok(container);
+        }
+        container.set_property(name, value).map_err(RuntimeError::from)?;
+        Ok(container)
+    }
+
+    /// Split an assignment target like `a.b[i]` into its base expression, the
+    /// base's value and the keys leading from it. The base is evaluated first,
+    /// then each index expression once, from left to right.
+    fn resolve_path<'a>(
+        &mut self,
+        expr: &'a Expr,
+        keys: &mut Vec<PathKey>,
+    ) -> Result<(&'a Expr, Value), RuntimeError> {
+        match expr {
+            Expr::Get(object, name) => {
+                let base = self.resolve_path(object, keys)?;
+                keys.push(PathKey::Field(name.lexeme.clone()));
+                Ok(base)
+            },
+            Expr::Index(object, _bracket, index) => {
+                let base = self.resolve_path(object, keys)?;
+                keys.push(PathKey::Index(self.evaluate(index)?));
+                Ok(base)
+            },
+            _ => Ok((expr, self.evaluate(expr)?)),
+        }
+    }
+
+    /// Store `value` at the end of `keys` inside `root`, the current value of
+    /// `base`. Arrays, objects and instances are shared and updated in place.
+    /// Only when the root itself is replaced, as a string or namespace is, is
+    /// the new value written back to the variable the path starts from.
+    fn store_path(&mut self, base: &Expr, root: Value, keys: &[PathKey], value: Value) -> Result<(), RuntimeError> {
+        let updated = self.set_path(root.clone(), keys, value)?;
+        if let Expr::Variable(name, binding) = base {
+            if !updated.shares_storage(&root) {
+                self.assign_variable(&name.lexeme, *binding, updated)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the value of an assignment target with `update(current)`,
+    /// evaluating the target's object and index expressions only once.
+    /// Returns the old and the new value.
+    fn update_target(
+        &mut self,
+        target: &Expr,
+        update: impl FnOnce(&mut Self, Value) -> Result<Value, RuntimeError>,
+    ) -> Result<(Value, Value), RuntimeError> {
+        let mut keys = Vec::new();
+        let (base, root) = self.resolve_path(target, &mut keys)?;
+        let current = self.get_path(root.clone(), &keys)?;
+        let updated = update(self, current.clone())?;
+        self.store_path(base, root, &keys, updated.clone())?;
+        Ok((current, updated))
+    }
+
+    fn get_path(&self, container: Value, keys: &[PathKey]) -> Result<Value, RuntimeError> {
+        keys.iter().try_fold(container, |value, key| match key {
+            PathKey::Field(name) => self.get_field(&value, name),
+            PathKey::Index(index) => self.get_index(value, index.clone()),
+        })
+    }
+
+    fn set_path(&self, container: Value, keys: &[PathKey], value: Value) -> Result<Value, RuntimeError> {
+        let Some((key, rest)) = keys.split_first() else {
+            return Ok(value);
+        };
+
+        let value = if rest.is_empty() {
+            value
+        } else {
+            let child = match key {
+                PathKey::Field(name) => self.get_field(&container, name)?,
+                PathKey::Index(index) => self.get_index(container.clone(), index.clone())?,
+            };
+            self.set_path(child, rest, value)?
+        };
+
+        match key {
+            PathKey::Field(name) => self.set_field(container, name, value),
+            PathKey::Index(index) => self.set_index(container, index.clone(), value),
+        }
+    }
+
     fn is_truthy(&self, value: &Value) -> bool {
         match value {
             Value::Nil => false,
@@ -590,19 +1412,13 @@ impl Interpreter {
         }
     }
 
-    fn is_equal(&self, a: &Value, b: &Value) -> bool {
-        match (a, b) {
-            (Value::Nil, Value::Nil) => true,
-            (Value::Nil, _) => false,
-            (_, Value::Nil) => false,
-            (Value::Boolean(a), Value::Boolean(b)) => a == b,
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            _ => false,
-        }
+    fn stringify(&self, value: Value) -> String {
+        self.stringify_nested(&value, &mut Vec::new())
     }
 
-    fn stringify(&self, value: Value) -> String {
+    /// `open` holds the arrays and objects being printed further up, so a
+    /// structure that contains itself prints as `[...]` or `{...}`.
+    fn stringify_nested(&self, value: &Value, open: &mut Vec<*const ()>) -> String {
         match value {
             Value::Number(n) => {
                 let text = n.to_string();
@@ -612,37 +1428,56 @@ impl Interpreter {
                     text
                 }
             },
-            Value::String(s) => s,
+            Value::String(s) => s.clone(),
             Value::Boolean(b) => b.to_string(),
             Value::Nil => "nil".to_string(),
             Value::Function(f) => format!("<fn {}>", f.name.lexeme),
             Value::NativeFunction(f) => format!("<native fn {}>", f.name),
             Value::Array(elements) => {
+                let key = Rc::as_ptr(elements) as *const ();
+                if open.contains(&key) {
+                    return "[...]".to_string();
+                }
+                open.push(key);
                 let mut result = String::from("[");
-                for (i, value) in elements.iter().enumerate() {
+                for (i, value) in elements.borrow().iter().enumerate() {
                     if i > 0 {
                         result.push_str(", ");
                     }
-                    result.push_str(&self.stringify(value.clone()));
+                    result.push_str(&self.stringify_nested(value, open));
                 }
-                result.push_str("]");
+                result.push(']');
+                open.pop();
                 result
             },
             Value::Object(properties) => {
+                let key = Rc::as_ptr(properties) as *const ();
+                if open.contains(&key) {
+                    return "{...}".to_string();
+                }
+                open.push(key);
+                // Sort the keys so printing an object is deterministic
+                let properties = properties.borrow();
+                let mut entries: Vec<(&String, &Value)> = properties.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+
                 let mut result = String::from("{");
-                for (i, (key, value)) in properties.iter().enumerate() {
+                for (i, (key, value)) in entries.into_iter().enumerate() {
                     if i > 0 {
                         result.push_str(", ");
                     }
-                    result.push_str(&format!("{}: {}", key, self.stringify(value.clone())));
+                    result.push_str(&format!("{}: {}", key, self.stringify_nested(value, open)));
                 }
-                result.push_str("}");
+                result.push('}');
+                open.pop();
                 result
             },
+            Value::Class(class) => format!("<class {}>", class.name),
+            Value::Instance(instance) => format!("<{} instance>", instance.borrow().class.name),
             Value::Namespace(ns_name, properties) => {
                 let mut result = String::new();
                 result.push_str("[Namespace: ");
-                result.push_str(&ns_name);
+                result.push_str(ns_name);
                 result.push_str(" {");
 
                 for (i, (key, _value)) in properties.iter().enumerate() {
@@ -662,13 +1497,14 @@ impl Interpreter {
 impl Value {
     // Helper method to check if a value is a callable function
     pub fn is_callable(&self) -> bool {
-        matches!(self, Value::Function(_) | Value::NativeFunction(_))
+        matches!(self, Value::Function(_) | Value::NativeFunction(_) | Value::Class(_))
     }
 
     // Helper method to get a property from an object or namespace
     pub fn get_property(&self, name: &str) -> Option<Value> {
         match self {
-            Value::Object(properties) | Value::Namespace(_, properties) => properties.get(name).cloned(),
+            Value::Object(properties) => properties.borrow().get(name).cloned(),
+            Value::Namespace(_, properties) => properties.get(name).cloned(),
             _ => None,
         }
     }
@@ -677,7 +1513,7 @@ impl Value {
     pub fn set_property(&mut self, name: &str, value: Value) -> Result<(), String> {
         match self {
             Value::Object(properties) => {
-                properties.insert(name.to_string(), value);
+                properties.borrow_mut().insert(name.to_string(), value);
                 Ok(())
             },
             Value::Namespace(_, properties) => {
@@ -690,7 +1526,66 @@ impl Value {
 
     // Create a new empty object
     pub fn new_object() -> Self {
-        Value::Object(HashMap::new())
+        Value::object(HashMap::new())
+    }
+
+    /// Whether both values are the same shared array, object or instance.
+    fn shares_storage(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// Wrap `elements` in a new shared array.
+    pub fn array(elements: Vec<Value>) -> Self {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+
+    /// Wrap `properties` in a new shared object.
+    pub fn object(properties: HashMap<String, Value>) -> Self {
+        Value::Object(Rc::new(RefCell::new(properties)))
+    }
+
+    /// Copy this value, recursively copying the arrays and objects it holds
+    /// so the result shares no storage with the original. A structure that
+    /// contains itself is copied with the same cycle.
+    pub fn deep_clone(&self) -> Value {
+        self.deep_clone_with(&mut HashMap::new())
+    }
+
+    fn deep_clone_with(&self, copies: &mut HashMap<*const (), Value>) -> Value {
+        match self {
+            Value::Array(elements) => {
+                let key = Rc::as_ptr(elements) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                copies.insert(key, Value::Array(Rc::clone(&copy)));
+                let cloned: Vec<Value> = elements.borrow().iter().map(|value| value.deep_clone_with(copies)).collect();
+                *copy.borrow_mut() = cloned;
+                Value::Array(copy)
+            },
+            Value::Object(properties) => {
+                let key = Rc::as_ptr(properties) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(HashMap::new()));
+                copies.insert(key, Value::Object(Rc::clone(&copy)));
+                let cloned: HashMap<String, Value> = properties
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.deep_clone_with(copies)))
+                    .collect();
+                *copy.borrow_mut() = cloned;
+                Value::Object(copy)
+            },
+            other => other.clone(),
+        }
     }
 
     // Create a new namespace with given name
@@ -698,3 +1593,1113 @@ impl Value {
         Value::Namespace(name.to_string(), HashMap::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_parser::Parser;
+    use crate::lexer::Lexer;
+    use crate::resolver::Resolver;
+    use crate::stdlib::Namespace;
+
+    // Helper function to run a script and return the interpreter for inspection.
+    // The script also runs on the bytecode backend, which must agree.
+    fn run(source: &str) -> Interpreter {
+        let interpreter = run_on(Backend::TreeWalker, source).unwrap();
+        let bytecode = run_on(Backend::Bytecode, source).unwrap();
+        assert_eq!(globals(&bytecode), globals(&interpreter), "backends disagree on {}", source);
+        interpreter
+    }
+
+    fn run_on(backend: Backend, source: &str) -> Result<Interpreter, Diagnostic> {
+        let statements = parse(source);
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+        interpreter.interpret(statements)?;
+        Ok(interpreter)
+    }
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+        let mut statements = Parser::new(tokens).parse().unwrap();
+        Resolver::new().resolve(&mut statements).unwrap();
+        statements
+    }
+
+    // The script's globals, printed, to compare runs on different backends
+    fn globals(interpreter: &Interpreter) -> Vec<(String, String)> {
+        let globals = interpreter.globals.borrow();
+        let mut values: Vec<(String, String)> = globals
+            .values
+            .iter()
+            .filter(|(_, value)| !matches!(value, Value::NativeFunction(_)))
+            .map(|(name, value)| (name.clone(), interpreter.stringify(value.clone())))
+            .collect();
+        values.sort();
+        values
+    }
+
+    // Helper function to read a global variable after a script has run
+    fn global(interpreter: &Interpreter, name: &str) -> Value {
+        interpreter.environment.borrow().get(name).unwrap()
+    }
+
+    #[test]
+    fn test_closure_counter() {
+        let interpreter = run("
+            fun make_counter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+            var counter = make_counter();
+            counter();
+            counter();
+            var result = counter();
+            var other = make_counter()();
+        ");
+        assert_eq!(global(&interpreter, "result"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "other"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_lexical_scope() {
+        // The function sees the `a` of its declaration scope, not the caller's
+        let interpreter = run("
+            var a = \"global\";
+            fun show() {
+                return a;
+            }
+            fun caller() {
+                var a = \"local\";
+                return show();
+            }
+            var result = caller();
+        ");
+        assert_eq!(global(&interpreter, "result"), Value::String("global".to_string()));
+    }
+
+    #[test]
+    fn test_resolved_locals() {
+        let interpreter = run("
+            var x = \"global\";
+            fun parity(n) {
+                // Local functions may call ones declared after them
+                fun is_even(n) { return n == 0 ? true : is_odd(n - 1); }
+                fun is_odd(n) { return n == 0 ? false : is_even(n - 1); }
+                return is_even(n);
+            }
+            var even = parity(10);
+            fun shadow() {
+                var before = x;
+                var x = \"local\";
+                { var x = \"inner\"; }
+                return before + \" \" + x;
+            }
+            var shadowed = shadow();
+            class Base { name() { return \"base\"; } }
+            class Derived < Base {
+                name() {
+                    var describe = () => super.name() + \" of \" + this.kind;
+                    return describe();
+                }
+            }
+            var d = Derived();
+            d.kind = \"derived\";
+            var described = d.name();
+        ");
+        assert_eq!(global(&interpreter, "even"), Value::Boolean(true));
+        assert_eq!(global(&interpreter, "shadowed"), Value::String("global local".to_string()));
+        assert_eq!(global(&interpreter, "described"), Value::String("base of derived".to_string()));
+
+        // A closure called before the local it refers to is defined
+        let error = run_error("fun f() {\n    fun g() { return later; }\n    g();\n    var later = 1;\n}\nf();");
+        assert_eq!(error.message, "Undefined variable 'later'.");
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let interpreter = run("
+            var a = true and false;
+            var b = false or \"fallback\";
+            var c = true && !false;
+            var d = nil || 0;
+            var calls = 0;
+            fun touch() {
+                calls = calls + 1;
+                return true;
+            }
+            var e = false and touch();
+            var f = true or touch();
+        ");
+        assert_eq!(global(&interpreter, "a"), Value::Boolean(false));
+        assert_eq!(global(&interpreter, "b"), Value::String("fallback".to_string()));
+        assert_eq!(global(&interpreter, "c"), Value::Boolean(true));
+        assert_eq!(global(&interpreter, "d"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "calls"), Value::Number(0.0));
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let interpreter = run("
+            var sum = 0;
+            for (var i = 1; i <= 4; i = i + 1) {
+                sum = sum + i;
+            }
+            var j = 0;
+            for (; j < 3;) j = j + 1;
+        ");
+        assert_eq!(global(&interpreter, "sum"), Value::Number(10.0));
+        assert_eq!(global(&interpreter, "j"), Value::Number(3.0));
+        // The loop variable is scoped to the loop
+        assert!(interpreter.environment.borrow().get("i").is_none());
+    }
+
+    #[test]
+    fn test_for_in_loop() {
+        let interpreter = run("
+            var total = 0;
+            for (n in [1, 2, 3]) {
+                total = total + n;
+            }
+            var weighted = 0;
+            for (i, n in [5, 6]) {
+                weighted = weighted + i * n;
+            }
+            var letters = \"\";
+            for (c in \"abc\") {
+                letters = c + letters;
+            }
+        ");
+        assert_eq!(global(&interpreter, "total"), Value::Number(6.0));
+        assert_eq!(global(&interpreter, "weighted"), Value::Number(6.0));
+        assert_eq!(global(&interpreter, "letters"), Value::String("cba".to_string()));
+    }
+
+    #[test]
+    fn test_for_in_object() {
+        let mut interpreter = run("var keys = \"\"; var sum = 0;");
+        let mut object = HashMap::new();
+        object.insert("b".to_string(), Value::Number(2.0));
+        object.insert("a".to_string(), Value::Number(1.0));
+        interpreter.environment.borrow_mut().define("obj".to_string(), Value::object(object));
+
+        let statements = parse("
+            for (key in obj) keys = keys + key;
+            for (key, value in obj) sum = sum + value;
+        ");
+        interpreter.interpret(statements).unwrap();
+
+        assert_eq!(global(&interpreter, "keys"), Value::String("ab".to_string()));
+        assert_eq!(global(&interpreter, "sum"), Value::Number(3.0));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let interpreter = run("
+            var sum = 0;
+            for (var i = 0; i < 100; i = i + 1) {
+                if (i > 9) break;
+                if (i == 3 or i == 5) continue;
+                sum = sum + i;
+            }
+            var steps = 0;
+            while (true) {
+                steps = steps + 1;
+                if (steps == 5) {
+                    break;
+                }
+            }
+            var seen = 0;
+            for (n in [1, 2, 3, 4]) {
+                if (n == 2) continue;
+                if (n == 4) break;
+                seen = seen + n;
+            }
+        ");
+        assert_eq!(global(&interpreter, "sum"), Value::Number(37.0));
+        assert_eq!(global(&interpreter, "steps"), Value::Number(5.0));
+        assert_eq!(global(&interpreter, "seen"), Value::Number(4.0));
+    }
+
+    #[test]
+    fn test_break_outside_loop_is_rejected() {
+        for source in ["break;", "continue;", "while (true) { fun f() { break; } }"] {
+            let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+            assert!(Parser::new(tokens).parse().is_err(), "{} should not parse", source);
+        }
+    }
+
+    #[test]
+    fn test_statement_ends_at_closing_brace() {
+        let statements = parse("while (true) { break }");
+        match &statements[0] {
+            Stmt::While(_, body) => assert!(matches!(&**body, Stmt::Block(block) if matches!(block[..], [Stmt::Break(_)]))),
+            other => panic!("expected a while loop, got {:?}", other),
+        }
+
+        let interpreter = run("
+            var n = 0;
+            while (true) { n = n + 1; if (n == 3) { break } else { continue } }
+            fun f() { return }
+            fun g() { return n }
+            var last = g();
+        ");
+        assert_eq!(global(&interpreter, "last"), Value::Number(3.0));
+    }
+
+    #[test]
+    fn test_classes_and_inheritance() {
+        let interpreter = run("
+            class Animal {
+                init(name) {
+                    this.name = name;
+                }
+                speak() {
+                    return this.name + \" makes a sound\";
+                }
+            }
+            class Dog < Animal {
+                init(name) {
+                    super.init(name);
+                    this.tricks = 0;
+                }
+                speak() {
+                    return super.speak() + \" (woof)\";
+                }
+                learn() {
+                    this.tricks = this.tricks + 1;
+                    return this;
+                }
+            }
+            var dog = Dog(\"Rex\");
+            var sound = dog.speak();
+            dog.learn().learn();
+            var tricks = dog.tricks;
+            var bound = dog.speak;
+            var bound_sound = bound();
+        ");
+        assert_eq!(global(&interpreter, "sound"), Value::String("Rex makes a sound (woof)".to_string()));
+        assert_eq!(global(&interpreter, "tricks"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "bound_sound"), global(&interpreter, "sound"));
+    }
+
+    #[test]
+    fn test_instances_share_state() {
+        // Instances are references: a copy sees writes made through the original
+        let interpreter = run("
+            class Box {}
+            var a = Box();
+            var b = a;
+            a.value = 42;
+            var seen = b.value;
+            var same = a == b;
+            var different = Box() == Box();
+        ");
+        assert_eq!(global(&interpreter, "seen"), Value::Number(42.0));
+        assert_eq!(global(&interpreter, "same"), Value::Boolean(true));
+        assert_eq!(global(&interpreter, "different"), Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_object_literal() {
+        let interpreter = run("
+            var empty = {};
+            var point = {
+                x: 1,
+                \"y coord\": 2,
+                nested: { label: \"origin\" },
+            };
+            var x = point.x;
+            var label = point.nested.label;
+        ");
+        assert_eq!(global(&interpreter, "empty"), Value::object(HashMap::new()));
+        assert_eq!(global(&interpreter, "x"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "label"), Value::String("origin".to_string()));
+        if let Value::Object(properties) = global(&interpreter, "point") {
+            let properties = properties.borrow();
+            assert_eq!(properties.get("y coord"), Some(&Value::Number(2.0)));
+        } else {
+            panic!("Expected object result");
+        }
+    }
+
+    #[test]
+    fn test_index_access() {
+        let interpreter = run("
+            var numbers = [10, 20, 30];
+            var second = numbers[1];
+            var letter = \"rusty\"[0];
+            var object = { key: \"value\" };
+            var looked_up = object[\"key\"];
+            var nested = [[1, 2], [3, 4]][1][0];
+        ");
+        assert_eq!(global(&interpreter, "second"), Value::Number(20.0));
+        assert_eq!(global(&interpreter, "letter"), Value::String("r".to_string()));
+        assert_eq!(global(&interpreter, "looked_up"), Value::String("value".to_string()));
+        assert_eq!(global(&interpreter, "nested"), Value::Number(3.0));
+    }
+
+    #[test]
+    fn test_index_assignment() {
+        let interpreter = run("
+            var grid = [[0, 0], [0, 0]];
+            grid[1][0] = 5;
+            var word = \"cat\";
+            word[0] = \"b\";
+            var record = { tags: [\"a\", \"b\"] };
+            record[\"owner\"] = \"me\";
+            record.tags[1] = \"z\";
+            var calls = 0;
+            fun position() {
+                calls = calls + 1;
+                return 0;
+            }
+            var list = [1];
+            list[position()] = 2;
+        ");
+        assert_eq!(
+            global(&interpreter, "grid"),
+            Value::array(vec![
+                Value::array(vec![Value::Number(0.0), Value::Number(0.0)]),
+                Value::array(vec![Value::Number(5.0), Value::Number(0.0)]),
+            ])
+        );
+        assert_eq!(global(&interpreter, "word"), Value::String("bat".to_string()));
+        assert_eq!(global(&interpreter, "list"), Value::array(vec![Value::Number(2.0)]));
+        assert_eq!(global(&interpreter, "calls"), Value::Number(1.0));
+
+        let mut expected = HashMap::new();
+        expected.insert("owner".to_string(), Value::String("me".to_string()));
+        expected.insert(
+            "tags".to_string(),
+            Value::array(vec![Value::String("a".to_string()), Value::String("z".to_string())]),
+        );
+        assert_eq!(global(&interpreter, "record"), Value::object(expected));
+    }
+
+    #[test]
+    fn test_index_assignment_evaluation_order() {
+        let interpreter = run("
+            var log = [];
+            fun note(step, value) { log.push(step); return value; }
+            var cells = [0, 0];
+            note(\"base\", cells)[note(\"index\", 1)] = note(\"value\", 5);
+
+            // The base is read before the index replaces the variable, as in JavaScript
+            var a = [1, 2];
+            var old = a;
+            fun replace() { a = [7, 8]; return 0; }
+            a[replace()] = 5;
+            var b = [1, 2];
+            var old_b = b;
+            b[0] = (b = [3, 4]);
+        ");
+        let strings = |steps: &[&str]| Value::array(steps.iter().map(|step| Value::String(step.to_string())).collect());
+        assert_eq!(global(&interpreter, "log"), strings(&["base", "index", "value"]));
+        assert_eq!(global(&interpreter, "cells"), Value::array(vec![Value::Number(0.0), Value::Number(5.0)]));
+        assert_eq!(global(&interpreter, "old"), Value::array(vec![Value::Number(5.0), Value::Number(2.0)]));
+        assert_eq!(global(&interpreter, "a"), Value::array(vec![Value::Number(7.0), Value::Number(8.0)]));
+        assert_eq!(global(&interpreter, "b"), Value::array(vec![Value::Number(3.0), Value::Number(4.0)]));
+        let replacement = Value::array(vec![Value::Number(3.0), Value::Number(4.0)]);
+        assert_eq!(global(&interpreter, "old_b"), Value::array(vec![replacement, Value::Number(2.0)]));
+    }
+
+    #[test]
+    fn test_property_assignment_evaluation_order() {
+        let interpreter = run("
+            var log = [];
+            fun note(step, value) { log.push(step); return value; }
+            var record = {field: 0};
+            note(\"object\", record).field = note(\"value\", 1);
+
+            // The object is read before the right-hand side replaces the variable
+            var o = {x: 1};
+            var old = o;
+            o.x = (o = {y: 2});
+        ");
+        let strings = |steps: &[&str]| Value::array(steps.iter().map(|step| Value::String(step.to_string())).collect());
+        assert_eq!(global(&interpreter, "log"), strings(&["object", "value"]));
+        assert_eq!(global(&interpreter, "record"), Value::object(HashMap::from([("field".to_string(), Value::Number(1.0))])));
+        let replacement = Value::object(HashMap::from([("y".to_string(), Value::Number(2.0))]));
+        assert_eq!(global(&interpreter, "o"), replacement.clone());
+        assert_eq!(global(&interpreter, "old"), Value::object(HashMap::from([("x".to_string(), replacement)])));
+    }
+
+    #[test]
+    fn test_arrays_and_objects_are_shared() {
+        let interpreter = run("
+            var numbers = [1, 2];
+            var alias = numbers;
+            alias.push(3);
+            fun append(list, value) { list.push(value); }
+            append(numbers, 4);
+            var last = numbers.pop();
+            numbers.set(0, 10);
+
+            var config = { retries: { count: 1 } };
+            var copy = config.clone();
+            copy.retries.count = 5;
+            var list_copy = numbers.clone();
+            list_copy.push(99);
+
+            var same = alias == numbers;
+            var equal_contents = [1] == [1];
+
+            var cycle = [];
+            cycle.push(cycle);
+            var printed = `${cycle}`;
+        ");
+        let expected = Value::array(vec![Value::Number(10.0), Value::Number(2.0), Value::Number(3.0)]);
+        assert_eq!(global(&interpreter, "numbers"), expected);
+        assert_eq!(global(&interpreter, "alias"), expected);
+        assert_eq!(global(&interpreter, "last"), Value::Number(4.0));
+        assert_eq!(
+            global(&interpreter, "config").get_property("retries").unwrap().get_property("count"),
+            Some(Value::Number(1.0))
+        );
+        assert_eq!(global(&interpreter, "same"), Value::Boolean(true));
+        assert_eq!(global(&interpreter, "equal_contents"), Value::Boolean(false));
+        assert_eq!(global(&interpreter, "printed"), Value::String("[[...]]".to_string()));
+    }
+
+    #[test]
+    fn test_assignment_writes_back_replaced_values_only() {
+        let interpreter = run("
+            var text = \"abc\";
+            text[0] = \"x\";
+            fun namespace_answer() {
+                var math = Math();
+                math.answer = 42;
+                return math.answer;
+            }
+            var answer = namespace_answer();
+            var nested = { inner: { text: \"ab\" } };
+            nested.inner.text[1] = \"z\";
+
+            var list = [1, 2];
+            var before = list;
+            fun grow() { list.push(3); return 0; }
+            list[grow()] = 9;
+        ");
+        assert_eq!(global(&interpreter, "text"), Value::String("xbc".to_string()));
+        assert_eq!(global(&interpreter, "answer"), Value::Number(42.0));
+        assert_eq!(
+            global(&interpreter, "nested").get_property("inner").unwrap().get_property("text"),
+            Some(Value::String("az".to_string()))
+        );
+        // The array is updated in place; the variable keeps the same array
+        let expected = Value::array(vec![Value::Number(9.0), Value::Number(2.0), Value::Number(3.0)]);
+        assert_eq!(global(&interpreter, "list"), expected);
+        assert!(global(&interpreter, "list").shares_storage(&global(&interpreter, "before")));
+    }
+
+    #[test]
+    fn test_property_assignment() {
+        let interpreter = run("
+            var config = { server: { port: 80 } };
+            config.server.port = 8080;
+            config.debug = true;
+            var alias = config;
+            alias.debug = false;
+        ");
+
+        let mut server = HashMap::new();
+        server.insert("port".to_string(), Value::Number(8080.0));
+        let mut expected = HashMap::new();
+        expected.insert("server".to_string(), Value::object(server));
+        // `alias` refers to the same object, so the last write wins for both
+        expected.insert("debug".to_string(), Value::Boolean(false));
+        assert_eq!(global(&interpreter, "config"), Value::object(expected));
+        assert_eq!(
+            global(&interpreter, "alias").get_property("debug"),
+            Some(Value::Boolean(false))
+        );
+    }
+
+    #[test]
+    fn test_modulo_power_and_floor_division() {
+        let interpreter = run("
+            var remainder = 7 % 3;
+            var negative_remainder = -7 % 3;
+            var power = 2 ** 3 ** 2;
+            var negated_power = -2 ** 2;
+            var floored = 7 ~/ 2;
+            var negative_floored = -7 ~/ 2;
+            var precedence = 1 + 2 * 3 % 4;
+        ");
+        assert_eq!(global(&interpreter, "remainder"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "negative_remainder"), Value::Number(-1.0));
+        assert_eq!(global(&interpreter, "power"), Value::Number(512.0));
+        assert_eq!(global(&interpreter, "negated_power"), Value::Number(-4.0));
+        assert_eq!(global(&interpreter, "floored"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "negative_floored"), Value::Number(-4.0));
+        assert_eq!(global(&interpreter, "precedence"), Value::Number(3.0));
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        for (source, message) in [
+            ("5 % 0;", "Modulo by zero."),
+            ("5 ~/ 0;", "Division by zero."),
+            ("\"a\" % 2;", "Operands of '%' must be numbers."),
+            ("2 ** nil;", "Operands of '**' must be numbers."),
+            ("true ~/ 2;", "Operands of '~/' must be numbers."),
+        ] {
+            let statements = parse(source);
+            let error = Interpreter::new().interpret(statements).unwrap_err();
+            assert_eq!(error.message, message);
+        }
+    }
+
+    #[test]
+    fn test_compound_assignment_and_increment() {
+        let interpreter = run("
+            var i = 0;
+            var post = i++;
+            var pre = ++i;
+            var total = 10;
+            total += 5;
+            total -= 3;
+            total *= 2;
+            total /= 4;
+            total %= 4;
+            var text = \"a\";
+            text += \"b\";
+            var point = { x: 1, ys: [1, 2] };
+            point.x += 2;
+            point.ys[1] *= 10;
+            point.ys[0]--;
+            var countdown = 3;
+            var before = countdown--;
+        ");
+        assert_eq!(global(&interpreter, "i"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "post"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "pre"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "total"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "text"), Value::String("ab".to_string()));
+        assert_eq!(global(&interpreter, "before"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "countdown"), Value::Number(2.0));
+        match global(&interpreter, "point") {
+            Value::Object(fields) => {
+                let fields = fields.borrow();
+                assert_eq!(fields["x"], Value::Number(3.0));
+                assert_eq!(fields["ys"], Value::array(vec![Value::Number(0.0), Value::Number(20.0)]));
+            }
+            other => panic!("Expected an object, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compound_assignment_evaluates_target_once() {
+        let interpreter = run("
+            var calls = 0;
+            fun next() { calls += 1; return 0; }
+            var items = [5];
+            items[next()] += 1;
+            items[next()]++;
+            class Counter {
+                init() { this.count = 0; }
+                bump() { return ++this.count; }
+            }
+            var counter = Counter();
+            counter.bump();
+            var bumped = counter.bump();
+        ");
+        assert_eq!(global(&interpreter, "calls"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "items"), Value::array(vec![Value::Number(7.0)]));
+        assert_eq!(global(&interpreter, "bumped"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_compound_assignment_errors() {
+        for (source, message) in [
+            ("var s = \"a\";\ns -= 1;", "Operands must be numbers."),
+            ("var n = 1;\nn /= 0;", "Division by zero."),
+            ("var s = \"a\";\ns++;", "Operand of '++' must be a number."),
+        ] {
+            let statements = parse(source);
+            let error = Interpreter::new().interpret(statements).unwrap_err();
+            assert_eq!(error.message, message);
+        }
+
+        for (source, error) in [
+            ("1 += 2;", Diagnostic::at("Invalid assignment target.", 1, 3)),
+            ("f()++;", Diagnostic::at("Invalid increment target.", 1, 4)),
+        ] {
+            let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+            assert_eq!(Parser::new(tokens).parse().unwrap_err(), vec![error]);
+        }
+    }
+
+    #[test]
+    fn test_conditional_and_nil_coalescing() {
+        let interpreter = run("
+            var n = 5;
+            var size = n > 10 ? \"huge\" : n > 3 ? \"big\" : \"small\";
+            var parity = n % 2 == 0
+                ? \"even\"
+                : \"odd\";
+            var fallback = nil ?? \"default\";
+            var kept_false = false ?? \"default\";
+            var kept_zero = 0 ?? 1;
+            var calls = 0;
+            fun count() { calls++; return calls; }
+            var skipped = 1 ?? count();
+            var chosen = true ? 1 : count();
+            var tight = n > 3 ?.5 : 1;
+            var fraction = .25 + 1;
+        ");
+        assert_eq!(global(&interpreter, "size"), Value::String("big".to_string()));
+        assert_eq!(global(&interpreter, "parity"), Value::String("odd".to_string()));
+        assert_eq!(global(&interpreter, "fallback"), Value::String("default".to_string()));
+        assert_eq!(global(&interpreter, "kept_false"), Value::Boolean(false));
+        assert_eq!(global(&interpreter, "kept_zero"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "skipped"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "chosen"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "calls"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "tight"), Value::Number(0.5));
+        assert_eq!(global(&interpreter, "fraction"), Value::Number(1.25));
+    }
+
+    #[test]
+    fn test_optional_chaining() {
+        let interpreter = run("
+            var config = { server: { port: 80 } };
+            var empty = nil;
+            var port = config?.server?.port;
+            var missing = empty?.server.port;
+            var called = empty?.keys();
+            var calls = 0;
+            fun count() { calls++; return calls; }
+            var skipped_args = empty?.method(count());
+            var length = \"abc\"?.length;
+            var with_default = empty?.server ?? \"none\";
+        ");
+        assert_eq!(global(&interpreter, "port"), Value::Number(80.0));
+        assert_eq!(global(&interpreter, "missing"), Value::Nil);
+        assert_eq!(global(&interpreter, "called"), Value::Nil);
+        assert_eq!(global(&interpreter, "skipped_args"), Value::Nil);
+        assert_eq!(global(&interpreter, "calls"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "length"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "with_default"), Value::String("none".to_string()));
+
+        // A short-circuit that escapes its chain is reported, never ignored
+        let error = Interpreter::new().report(RuntimeError::ShortCircuit).unwrap_err();
+        assert!(error.message.starts_with("Internal error"));
+
+        // Only the optional access is guarded; a plain access on nil still fails
+        let error = run_error("var empty = nil;\nprint empty?.a;\nprint empty.a;");
+        assert_eq!(error.message, "Cannot access properties of non-object value.");
+        assert_eq!(error.location, Some(Location::new(3, 13)));
+
+        for (source, error) in [
+            ("a?.b = 1;", Diagnostic::at("Invalid assignment target.", 1, 6)),
+            ("var x = a ? 1;", Diagnostic::at("Expect ':' in conditional expression, found ';'.", 1, 14)),
+        ] {
+            let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+            assert_eq!(Parser::new(tokens).parse().unwrap_err(), vec![error]);
+        }
+    }
+
+    #[test]
+    fn test_string_escapes_and_raw_strings() {
+        let interpreter = run(r#"
+            var escaped = "tab\there\n\"quoted\" \\ \u{1F600}";
+            var raw = r"C:\temp\d+";
+        "#);
+        assert_eq!(
+            global(&interpreter, "escaped"),
+            Value::String("tab\there\n\"quoted\" \\ \u{1F600}".to_string())
+        );
+        assert_eq!(global(&interpreter, "raw"), Value::String(r"C:\temp\d+".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_escape_reports_position() {
+        let error = Lexer::new("var a = 1;\nvar b = \"ok \\q\";".to_string()).scan_tokens().unwrap_err();
+        assert_eq!(error, Diagnostic::at("Invalid escape sequence '\\q'.", 2, 13));
+
+        let error = Lexer::new("\"\\u{110000}\"".to_string()).scan_tokens().unwrap_err();
+        assert_eq!(error, Diagnostic::at("Invalid unicode escape: '110000' is not a valid code point.", 1, 2));
+    }
+
+    #[test]
+    fn test_template_literals() {
+        let interpreter = run(r#"
+            var name = "Ann";
+            var age = 41;
+            var greeting = `Hello ${name}, you are ${age + 1}`;
+            var mixed = `${[1, 2]} ${ {a: true}["a"] } ${nil}`;
+            var nested = `outer ${`inner ${age}`}`;
+            var escaped = `\${name} \``;
+        "#);
+        assert_eq!(global(&interpreter, "greeting"), Value::String("Hello Ann, you are 42".to_string()));
+        assert_eq!(global(&interpreter, "mixed"), Value::String("[1, 2] true nil".to_string()));
+        assert_eq!(global(&interpreter, "nested"), Value::String("outer inner 41".to_string()));
+        assert_eq!(global(&interpreter, "escaped"), Value::String("${name} `".to_string()));
+    }
+
+    #[test]
+    fn test_unterminated_template_literal() {
+        for source in ["`open", "`value ${1 + 2`"] {
+            let error = Lexer::new(source.to_string()).scan_tokens().unwrap_err();
+            assert_eq!(error.message, "Unterminated template literal.");
+        }
+    }
+
+    #[test]
+    fn test_function_expressions_and_arrows() {
+        let interpreter = run("
+            var add = (a, b) => a + b;
+            var sum = add(2, 3);
+            var twice = fun (f, x) { return f(f(x)); };
+            var scaled = twice((n) => n * 10, 2);
+            var make_counter = () => {
+                var count = 0;
+                return () => {
+                    count = count + 1;
+                    return count;
+                };
+            };
+            var counter = make_counter();
+            counter();
+            var counted = counter();
+            var tools = { double: (x) => x * 2 };
+            var doubled = tools.double(4);
+            var grouped = (1 + 2) * 3;
+        ");
+        assert_eq!(global(&interpreter, "sum"), Value::Number(5.0));
+        assert_eq!(global(&interpreter, "scaled"), Value::Number(200.0));
+        assert_eq!(global(&interpreter, "counted"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "doubled"), Value::Number(8.0));
+        assert_eq!(global(&interpreter, "grouped"), Value::Number(9.0));
+    }
+
+    #[test]
+    fn test_throw_and_catch() {
+        let interpreter = run(r#"
+            var log = "";
+            fun check(n) {
+                if (n > 2) {
+                    throw { kind: "RangeError", message: "too big" };
+                }
+                return n;
+            }
+            try {
+                check(5);
+                log = log + "unreachable ";
+            } catch (e) {
+                log = log + e.kind + ": " + e.message + ", ";
+            } finally {
+                log = log + "finally";
+            }
+
+            var caught = nil;
+            try {
+                throw "plain value";
+            } catch (e) {
+                caught = e;
+            }
+
+            var runtime_kind = nil;
+            try {
+                var broken = 1 / 0;
+            } catch (e) {
+                runtime_kind = e.kind;
+            }
+
+            var native_kind = nil;
+            try {
+                File().read("/this/path/does/not/exist");
+            } catch (e) {
+                native_kind = e.kind;
+            }
+        "#);
+        assert_eq!(global(&interpreter, "log"), Value::String("RangeError: too big, finally".to_string()));
+        assert_eq!(global(&interpreter, "caught"), Value::String("plain value".to_string()));
+        assert_eq!(global(&interpreter, "runtime_kind"), Value::String("RuntimeError".to_string()));
+        assert_eq!(global(&interpreter, "native_kind"), Value::String("NativeError".to_string()));
+    }
+
+    #[test]
+    fn test_finally_runs_on_return_and_continue() {
+        let interpreter = run("
+            var cleanups = 0;
+            fun early() {
+                try {
+                    return \"from try\";
+                } finally {
+                    cleanups = cleanups + 1;
+                }
+            }
+            var returned = early();
+            for (var i = 0; i < 3; i = i + 1) {
+                try {
+                    continue;
+                } finally {
+                    cleanups = cleanups + 1;
+                }
+            }
+        ");
+        assert_eq!(global(&interpreter, "returned"), Value::String("from try".to_string()));
+        assert_eq!(global(&interpreter, "cleanups"), Value::Number(4.0));
+    }
+
+    #[test]
+    fn test_finally_block_is_compiled_once() {
+        let interpreter = run("
+            var log = [];
+            fun nested() {
+                try {
+                    try {
+                        return \"inner\";
+                    } finally {
+                        log.push(\"first\");
+                    }
+                } finally {
+                    log.push(\"second\");
+                }
+            }
+            var returned = nested();
+            fun overridden() {
+                while (true) {
+                    try {
+                        return \"dropped\";
+                    } finally {
+                        break;
+                    }
+                }
+                return \"after the loop\";
+            }
+            var kept = overridden();
+            fun from_loop() {
+                for (x in [1, 2, 3]) {
+                    try {
+                        if (x == 2) return x;
+                    } finally {
+                        log.push(x);
+                    }
+                }
+            }
+            var found = from_loop();
+        ");
+        assert_eq!(global(&interpreter, "returned"), Value::String("inner".to_string()));
+        assert_eq!(global(&interpreter, "kept"), Value::String("after the loop".to_string()));
+        assert_eq!(global(&interpreter, "found"), Value::Number(2.0));
+        let log = ["first", "second"].map(|step| Value::String(step.to_string()));
+        let log = log.into_iter().chain([Value::Number(1.0), Value::Number(2.0)]).collect();
+        assert_eq!(global(&interpreter, "log"), Value::array(log));
+
+        // Every way out of the try statement shares the one finally block
+        let statements = parse("
+            for (var i = 0; i < 3; i++) {
+                try {
+                    if (i == 0) continue;
+                    if (i == 1) break;
+                    throw i;
+                } catch (error) {
+                    if (error) break;
+                } finally {
+                    print \"cleanup\";
+                }
+            }
+        ");
+        let chunk = Compiler::new().compile(&statements);
+        let prints = chunk.code.iter().filter(|op| matches!(op, crate::compiler::Op::Print)).count();
+        assert_eq!(prints, 1);
+    }
+
+    #[test]
+    fn test_uncaught_exception() {
+        for (source, message) in [
+            ("throw { kind: \"Fatal\", message: \"bye\" };", "Fatal: bye"),
+            ("throw 42;", "Uncaught exception: 42"),
+        ] {
+            let statements = parse(source);
+            let error = Interpreter::new().interpret(statements).unwrap_err();
+            assert_eq!(error.message, message);
+        }
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let tokens = Lexer::new("var a = 1;\nprint foo(a;".to_string()).scan_tokens().unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(errors, vec![Diagnostic::at("Expect ')' after arguments, found ';'.", 2, 12)]);
+    }
+
+    #[test]
+    fn test_resolution_errors_are_reported_after_parsing() {
+        let source = "{\n    var a = a + 1;\n}";
+        let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+        let mut statements = Parser::new(tokens).parse().unwrap();
+        let errors = Resolver::new().resolve(&mut statements).unwrap_err();
+        let error = Diagnostic::at("Can't read local variable in its own initializer.", 2, 13);
+        assert_eq!(errors, vec![error.clone()]);
+
+        let result = crate::run(&mut Interpreter::new(), source);
+        assert_eq!(result, Err(crate::Error::Resolution(vec![error])));
+        let result = crate::run(&mut Interpreter::new(), "print a +;");
+        assert!(matches!(result, Err(crate::Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parser_recovers_and_reports_every_error() {
+        let source = "var a = ;\nprint \"kept\";\nfun f() {\n    var b = 1 2;\n    return b;\n}\nprint a +;\n";
+        let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+        let (statements, errors) = Parser::new(tokens).parse_partial();
+
+        assert_eq!(
+            errors,
+            vec![
+                Diagnostic::at("Expect expression, found ';'.", 1, 9),
+                Diagnostic::at("Expect ';' or newline after variable declaration, found '2'.", 4, 15),
+                Diagnostic::at("Expect expression, found ';'.", 7, 10),
+            ]
+        );
+
+        // The print statement and the function (minus its broken line) survive
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(&statements[0], Stmt::Print(_)));
+        match &statements[1] {
+            Stmt::Function(name, _, body) => {
+                assert_eq!(name.lexeme, "f");
+                assert_eq!(body.len(), 1);
+            }
+            other => panic!("expected a function, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parser_recovery_skips_stray_closing_brace() {
+        let tokens = Lexer::new("}\nprint 1;\n".to_string()).scan_tokens().unwrap();
+        let (statements, errors) = Parser::new(tokens).parse_partial();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(statements.len(), 1);
+    }
+
+    #[test]
+    fn test_location_after_multiline_string() {
+        for source in ["\"a\nbc\" x", "r\"a\nbc\" x", "`a\nbc` x"] {
+            let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+            let x = tokens.iter().find(|token| token.lexeme == "x").unwrap();
+            assert_eq!((x.line, x.column), (2, 5), "in {:?}", source);
+        }
+
+        let error = run_error("var s = \"first\nsecond\"; print nil.x;");
+        assert_eq!(error.location, Some(Location::new(2, 20)));
+    }
+
+    #[test]
+    fn test_runtime_error_location() {
+        let source = "fun divide(n) {\n    return n / 0;\n}\nvar x = divide(4);";
+        let statements = parse(source);
+        let error = Interpreter::new().interpret(statements).unwrap_err();
+        assert_eq!(error.message, "Division by zero.");
+        assert_eq!(error.location, Some(Location::new(2, 14)));
+
+        let statements = parse("throw \"boom\";");
+        let error = Interpreter::new().interpret(statements).unwrap_err();
+        assert_eq!(error, Diagnostic::at("Uncaught exception: boom", 1, 1));
+    }
+
+    fn run_error(source: &str) -> Diagnostic {
+        let error = run_on(Backend::TreeWalker, source).err().unwrap();
+        let bytecode = run_on(Backend::Bytecode, source).err().unwrap();
+        assert_eq!(bytecode, error, "backends disagree on {}", source);
+        error
+    }
+
+    #[test]
+    fn test_recursion_depth_limit() {
+        let source = "fun f(n) { return f(n + 1); }\nf(0);";
+        let error = run_error(source);
+        assert_eq!(error.message, "Maximum recursion depth exceeded.");
+        assert_eq!(error.call_stack.len(), DEFAULT_MAX_CALL_DEPTH);
+
+        let interpreter = run("
+            fun f(n) { return f(n + 1); }
+            var message = nil;
+            try { f(0); } catch (e) { message = e.message; }
+            fun sum(n) {
+                if (n == 0) { return 0; }
+                return n + sum(n - 1);
+            }
+            var total = sum(9000);
+        ");
+        assert_eq!(
+            global(&interpreter, "message"),
+            Value::String("Maximum recursion depth exceeded.".to_string())
+        );
+        assert_eq!(global(&interpreter, "total"), Value::Number(40504500.0));
+
+        let statements = parse(source);
+        let mut interpreter = Interpreter::new();
+        interpreter.set_max_call_depth(50);
+        let error = interpreter.interpret(statements).unwrap_err();
+        assert_eq!(error.call_stack.len(), 50);
+    }
+
+    #[test]
+    fn test_runtime_error_call_stack() {
+        let error = run_error(
+            "fun inner(n) {\n    return n / 0;\n}\nfun outer(n) {\n    return inner(n) + 1;\n}\nouter(3);",
+        );
+        let frames: Vec<(&str, usize)> = error
+            .call_stack
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.line))
+            .collect();
+        assert_eq!(frames, vec![("outer", 7), ("inner", 5)]);
+
+        // Frames of a call whose error was caught must not leak into a later error
+        let error = run_error("fun f() {\n    try { return 1 / 0; } catch (e) { return 2; }\n}\nf();\nprint 1 + nil;");
+        assert!(error.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_traceback_of_error_dropped_by_finally() {
+        let frames = |error: Diagnostic| -> Vec<String> {
+            error.call_stack.iter().map(|frame| frame.function.clone()).collect()
+        };
+
+        // A finally block that breaks out drops the pending error and its frames
+        let error = run_error(
+            "fun inner(){return 1+nil;} fun swallow(){for(var i=0;i<1;i++){try{inner();}finally{break;}}} swallow(); fun other(){return nil.x;} other();",
+        );
+        assert_eq!(frames(error), vec!["other"]);
+
+        // A finally block that completes rethrows the error with its frames
+        let error = run_error("fun inner() { return 1 + nil; }\nfun f() { try { inner(); } finally { var x = 1; } }\nf();");
+        assert_eq!(frames(error), vec!["f", "inner"]);
+
+        // An error raised by the finally block itself records its own frames
+        let error = run_error("fun inner() { return 1 + nil; }\nfun fail() { return nil.x; }\nfun f() { try { inner(); } finally { fail(); } }\nf();");
+        assert_eq!(frames(error), vec!["f", "fail"]);
+    }
+
+    #[test]
+    fn test_interpreters_with_different_stdlibs() {
+        let source = "var exists = File().exists(\"Cargo.toml\");";
+        let statements = || parse(source);
+
+        // Both interpreters live in the same process with their own namespaces
+        let mut sandboxed = Interpreter::with_stdlib(StdLib::builder().without(Namespace::File).build());
+        let mut full = Interpreter::new();
+
+        let error = sandboxed.interpret(statements()).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'File'.");
+        full.interpret(statements()).unwrap();
+        assert_eq!(global(&full, "exists"), Value::Boolean(true));
+
+        // The other namespaces are still there
+        sandboxed.set_backend(Backend::Bytecode);
+        let statements = parse("var pi = Math().PI;");
+        sandboxed.interpret(statements).unwrap();
+        assert_eq!(global(&sandboxed, "pi"), Value::Number(std::f64::consts::PI));
+    }
+}
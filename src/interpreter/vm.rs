@@ -0,0 +1,454 @@
+//! The stack machine that runs the bytecode produced by `crate::compiler`.
+//!
+//! Calls to user functions recurse through `Interpreter::call_function`, so
+//! both backends share the call stack, the recursion limit and the error
+//! tracebacks. Each call runs its chunk in `run`, with the operands of all
+//! active calls on the interpreter's value stack.
+
+use super::{Binding, Body, CallFrame, Class, Environment, Function, Interpreter, PathKey, RuntimeError, Value};
+use super::{STACK_RED_ZONE, STACK_SEGMENT_SIZE};
+use crate::compiler::{BinaryOp, Chunk, Op, PathStep};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// A try statement being run, registered by `Op::Try` or `Op::TryFinally`
+struct Handler {
+    target: usize,
+    // Whether the error is handed to a catch clause rather than kept pending
+    // for a finally block
+    catches: bool,
+    environment: Rc<RefCell<Environment>>,
+    stack_height: usize,
+    pending_height: usize,
+}
+
+// Why a finally block is running, which says what to do once it completes
+enum Completion {
+    // Continue at the instruction after the `CallFinally`, with the value it
+    // took along, such as the value being returned
+    Resume(usize, Option<Value>),
+    // Rethrow an error raised in the try statement, with its traceback
+    Error(RuntimeError, Vec<CallFrame>),
+}
+
+// The state of one call's run of a chunk
+struct Frame {
+    ip: usize,
+    handlers: Vec<Handler>,
+    // One for each finally block running. A finally block that exits early
+    // drops its own.
+    pending: Vec<Completion>,
+}
+
+impl Interpreter {
+    /// Run `chunk` with `environment` as its scope and return the value it
+    /// returns.
+    pub(super) fn run_chunk(&mut self, chunk: &Chunk, environment: Rc<RefCell<Environment>>) -> Result<Value, RuntimeError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || {
+            self.with_environment(environment, |interpreter| interpreter.run(chunk))
+        })
+    }
+
+    fn run(&mut self, chunk: &Chunk) -> Result<Value, RuntimeError> {
+        let base = self.stack.len();
+        let mut frame = Frame {
+            ip: 0,
+            handlers: Vec::new(),
+            pending: Vec::new(),
+        };
+        let result = loop {
+            let error = match self.execute_ops(chunk, &mut frame) {
+                Ok(value) => break Ok(value),
+                Err(error) => error.at(chunk.location(frame.ip - 1)),
+            };
+            let Some(handler) = frame.handlers.pop() else {
+                break Err(error);
+            };
+
+            self.environment = handler.environment;
+            self.stack.truncate(handler.stack_height);
+            frame.pending.truncate(handler.pending_height);
+            frame.ip = handler.target;
+            if handler.catches {
+                match Self::exception_value(error) {
+                    Ok(exception) => {
+                        // The error was handled, so its traceback is no longer needed
+                        self.traceback.clear();
+                        self.stack.push(exception);
+                    }
+                    Err(error) => break Err(error),
+                }
+            } else {
+                // Set the traceback aside so errors raised by the finally
+                // block record their own
+                let traceback = std::mem::take(&mut self.traceback);
+                frame.pending.push(Completion::Error(error, traceback));
+            }
+        };
+        self.stack.truncate(base);
+        result
+    }
+
+    fn execute_ops(&mut self, chunk: &Chunk, frame: &mut Frame) -> Result<Value, RuntimeError> {
+        loop {
+            let op = chunk.code[frame.ip];
+            frame.ip += 1;
+            match op {
+                Op::Constant(constant) => self.stack.push(chunk.constants[constant as usize].clone()),
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Boolean(true)),
+                Op::False => self.stack.push(Value::Boolean(false)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Dup => {
+                    let value = self.peek().clone();
+                    self.stack.push(value);
+                }
+                Op::GetLocal(name, depth, slot) => {
+                    let binding = Binding { depth: depth as usize, slot: slot as usize };
+                    let value = self.lookup_variable(&chunk.names[name as usize], Some(binding))?;
+                    self.stack.push(value);
+                }
+                Op::SetLocal(name, depth, slot) => {
+                    let binding = Binding { depth: depth as usize, slot: slot as usize };
+                    let value = self.peek().clone();
+                    self.assign_variable(&chunk.names[name as usize], Some(binding), value)?;
+                }
+                Op::DefineLocal => {
+                    let value = self.pop();
+                    self.environment.borrow_mut().slots.push(value);
+                }
+                Op::GetGlobal(name) => {
+                    let value = self.lookup_variable(&chunk.names[name as usize], None)?;
+                    self.stack.push(value);
+                }
+                Op::SetGlobal(name) => {
+                    let value = self.peek().clone();
+                    self.assign_variable(&chunk.names[name as usize], None, value)?;
+                }
+                Op::DefineGlobal(name) => {
+                    let value = self.pop();
+                    self.globals.borrow_mut().define(chunk.names[name as usize].clone(), value);
+                }
+                Op::GetQualified(name, target) => {
+                    let global = match &chunk.constants[name as usize] {
+                        Value::String(name) => self.globals.borrow().get(name),
+                        _ => None,
+                    };
+                    match global {
+                        Some(value) => self.stack.push(value),
+                        None => frame.ip = target as usize,
+                    }
+                }
+                Op::GetProperty(name) => {
+                    let object = self.pop();
+                    let value = self.get_member(&object, &chunk.names[name as usize])?;
+                    self.stack.push(value);
+                }
+                Op::Index => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = self.get_index(object, index)?;
+                    self.stack.push(value);
+                }
+                Op::GetPath(path) => {
+                    // The base is below the index values
+                    let steps = &chunk.paths[path as usize];
+                    let keys = self.path_keys(steps, self.stack.len());
+                    let indexes = keys.iter().filter(|key| matches!(key, PathKey::Index(_))).count();
+                    let root = self.stack[self.stack.len() - indexes - 1].clone();
+                    let value = self.get_path(root, &keys)?;
+                    self.stack.push(value);
+                }
+                Op::SetPath(path, postfix) => {
+                    let steps = &chunk.paths[path as usize];
+                    let value = self.pop();
+                    let result = if postfix { self.pop() } else { value.clone() };
+                    let keys = self.path_keys(steps, self.stack.len());
+                    let indexes = keys.iter().filter(|key| matches!(key, PathKey::Index(_))).count();
+                    self.stack.truncate(self.stack.len() - indexes);
+                    let root = self.pop();
+                    let updated = self.set_path(root.clone(), &keys, value)?;
+                    let replaced = !updated.shares_storage(&root);
+                    self.stack.push(result);
+                    self.stack.push(updated);
+                    self.stack.push(Value::Boolean(replaced));
+                }
+                Op::Binary(operator) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let value = match (operator, &left, &right) {
+                        (BinaryOp::Add, Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (BinaryOp::Subtract, Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+                        (BinaryOp::Multiply, Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+                        (BinaryOp::Less, Value::Number(a), Value::Number(b)) => Value::Boolean(a < b),
+                        (BinaryOp::LessEqual, Value::Number(a), Value::Number(b)) => Value::Boolean(a <= b),
+                        (BinaryOp::Greater, Value::Number(a), Value::Number(b)) => Value::Boolean(a > b),
+                        (BinaryOp::GreaterEqual, Value::Number(a), Value::Number(b)) => Value::Boolean(a >= b),
+                        _ => self.binary_op(&operator.token_type(), left, right)?,
+                    };
+                    self.stack.push(value);
+                }
+                Op::Negate => match self.pop() {
+                    Value::Number(n) => self.stack.push(Value::Number(-n)),
+                    _ => return Err(RuntimeError::Error("Operand must be a number.".to_string(), None)),
+                },
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!self.is_truthy(&value)));
+                }
+                Op::Increment | Op::Decrement => {
+                    let (step, operator) = if op == Op::Increment { (1.0, "++") } else { (-1.0, "--") };
+                    match self.pop() {
+                        Value::Number(n) => self.stack.push(Value::Number(n + step)),
+                        _ => {
+                            return Err(RuntimeError::Error(
+                                format!("Operand of '{}' must be a number.", operator),
+                                None,
+                            ))
+                        }
+                    }
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    println!("{}", self.stringify(value));
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    let condition = self.pop();
+                    if !self.is_truthy(&condition) {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::And(target) | Op::Or(target) | Op::Coalesce(target) => {
+                    let value = self.peek();
+                    let decided = match op {
+                        Op::And(_) => !self.is_truthy(value),
+                        Op::Or(_) => self.is_truthy(value),
+                        _ => *value != Value::Nil,
+                    };
+                    if decided {
+                        frame.ip = target as usize;
+                    } else {
+                        self.pop();
+                    }
+                }
+                Op::JumpIfNil(target) => {
+                    if *self.peek() == Value::Nil {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::Call(count) => {
+                    let args = self.stack.split_off(self.stack.len() - count as usize);
+                    let callee = self.pop();
+                    let value = self.call_value(&callee, args, Self::call_line(chunk, frame))?;
+                    self.stack.push(value);
+                }
+                Op::Invoke(name, count) => {
+                    let args = self.stack.split_off(self.stack.len() - count as usize);
+                    let object = self.pop();
+                    let value = self.invoke(object, &chunk.names[name as usize], args, Self::call_line(chunk, frame))?;
+                    self.stack.push(value);
+                }
+                Op::Closure(function) => {
+                    let prototype = &chunk.functions[function as usize];
+                    let function = Function {
+                        name: prototype.name.clone(),
+                        params: prototype.params.clone(),
+                        body: Body::Compiled(Rc::clone(&prototype.chunk)),
+                        closure: Rc::clone(&self.environment),
+                        is_initializer: false,
+                    };
+                    self.stack.push(Value::Function(Rc::new(function)));
+                }
+                Op::Class(class, has_superclass) => {
+                    let prototype = &chunk.classes[class as usize];
+                    let superclass = match has_superclass {
+                        true => match self.pop() {
+                            Value::Class(class) => Some(class),
+                            _ => return Err(RuntimeError::Error("Superclass must be a class.".to_string(), None)),
+                        },
+                        false => None,
+                    };
+
+                    let method_closure = self.method_closure(&superclass);
+                    let methods = prototype
+                        .methods
+                        .iter()
+                        .map(|method| {
+                            let function = Function {
+                                name: method.name.clone(),
+                                params: method.params.clone(),
+                                body: Body::Compiled(Rc::clone(&method.chunk)),
+                                closure: Rc::clone(&method_closure),
+                                is_initializer: method.name.lexeme == "init",
+                            };
+                            (method.name.lexeme.clone(), Rc::new(function))
+                        })
+                        .collect();
+                    let class = Class {
+                        name: prototype.name.lexeme.clone(),
+                        superclass,
+                        methods,
+                    };
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+                Op::Super(method, depth) => {
+                    // Methods bind `this` in the scope just inside the one binding `super`
+                    let depth = depth as usize;
+                    let environment = self.environment.borrow();
+                    let superclass = environment.get_at(Binding { depth, slot: 0 });
+                    let this = depth.checked_sub(1).and_then(|depth| environment.get_at(Binding { depth, slot: 0 }));
+                    drop(environment);
+                    let method = &chunk.names[method as usize];
+                    let value = match (superclass, this) {
+                        (Some(Value::Class(superclass)), Some(instance)) => match superclass.find_method(method) {
+                            Some(found) => Value::Function(found.bind(instance)),
+                            None => {
+                                return Err(RuntimeError::Error(format!("Undefined property '{}'.", method), None))
+                            }
+                        },
+                        _ => {
+                            return Err(RuntimeError::Error(
+                                "Can't use 'super' outside of a subclass method.".to_string(),
+                                None,
+                            ))
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                Op::Array(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count as usize);
+                    self.stack.push(Value::array(elements));
+                }
+                Op::Object(count) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                    let mut object = HashMap::new();
+                    let mut entries = entries.into_iter();
+                    while let (Some(Value::String(key)), Some(value)) = (entries.next(), entries.next()) {
+                        object.insert(key, value);
+                    }
+                    self.stack.push(Value::object(object));
+                }
+                Op::Template(count) => {
+                    let parts = self.stack.split_off(self.stack.len() - count as usize);
+                    let mut text = String::new();
+                    for part in parts {
+                        text.push_str(&self.stringify(part));
+                    }
+                    self.stack.push(Value::String(text));
+                }
+                Op::EnterScope => {
+                    let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                    self.environment = Rc::new(RefCell::new(environment));
+                }
+                Op::ExitScope => {
+                    let enclosing = self.environment.borrow().enclosing.clone();
+                    if let Some(enclosing) = enclosing {
+                        self.environment = enclosing;
+                    }
+                }
+                Op::ForInPrepare(with_index) => {
+                    let iterable = self.pop();
+                    let (positions, elements) = self.for_in_pairs(&iterable, with_index)?.into_iter().unzip();
+                    self.stack.push(Value::array(positions));
+                    self.stack.push(Value::array(elements));
+                    self.stack.push(Value::Number(0.0));
+                }
+                Op::ForInNext(exit, with_index) => {
+                    let length = self.stack.len();
+                    let (Value::Array(positions), Value::Array(elements), Value::Number(counter)) =
+                        (&self.stack[length - 3], &self.stack[length - 2], &self.stack[length - 1])
+                    else {
+                        unreachable!("for-in loop state missing from the stack");
+                    };
+                    let position = *counter as usize;
+                    let (Some(index), Some(item)) = (positions.borrow().get(position).cloned(), elements.borrow().get(position).cloned()) else {
+                        frame.ip = exit as usize;
+                        continue;
+                    };
+                    self.stack[length - 1] = Value::Number(counter + 1.0);
+
+                    // Every iteration gets a fresh scope so closures created in
+                    // the body capture that iteration's values
+                    let mut environment = Environment::new(Some(Rc::clone(&self.environment)));
+                    if with_index {
+                        environment.slots.push(index);
+                    }
+                    environment.slots.push(item);
+                    self.environment = Rc::new(RefCell::new(environment));
+                }
+                Op::Try(target) | Op::TryFinally(target) => frame.handlers.push(Handler {
+                    target: target as usize,
+                    catches: matches!(op, Op::Try(_)),
+                    environment: Rc::clone(&self.environment),
+                    stack_height: self.stack.len(),
+                    pending_height: frame.pending.len(),
+                }),
+                Op::PopHandler => {
+                    frame.handlers.pop();
+                }
+                Op::CallFinally(target, with_value) => {
+                    let value = if with_value { Some(self.pop()) } else { None };
+                    frame.pending.push(Completion::Resume(frame.ip, value));
+                    frame.ip = target as usize;
+                }
+                Op::EndFinally => match frame.pending.pop() {
+                    Some(Completion::Resume(resume, value)) => {
+                        self.stack.extend(value);
+                        frame.ip = resume;
+                    }
+                    Some(Completion::Error(error, traceback)) => {
+                        self.traceback = traceback;
+                        return Err(error);
+                    }
+                    None => {}
+                },
+                Op::DropCompletion => {
+                    frame.pending.pop();
+                }
+                Op::Throw => {
+                    let value = self.pop();
+                    return Err(RuntimeError::Throw(value, chunk.location(frame.ip - 1)));
+                }
+                Op::Fail(message) => {
+                    let message = match &chunk.constants[message as usize] {
+                        Value::String(message) => message.clone(),
+                        _ => String::new(),
+                    };
+                    return Err(RuntimeError::Error(message, None));
+                }
+                Op::Return => return Ok(self.pop()),
+            }
+        }
+    }
+
+    /// Build the keys of an assignment path from its steps, taking index
+    /// values from the stack below position `end`.
+    fn path_keys(&self, steps: &[PathStep], end: usize) -> Vec<PathKey> {
+        let indexes = steps.iter().filter(|step| matches!(step, PathStep::Index)).count();
+        let mut values = self.stack[end - indexes..end].iter();
+        steps
+            .iter()
+            .map(|step| match step {
+                PathStep::Field(name) => PathKey::Field(name.clone()),
+                PathStep::Index => PathKey::Index(values.next().cloned().unwrap_or(Value::Nil)),
+            })
+            .collect()
+    }
+
+    /// The line of the call made by the instruction just executed.
+    fn call_line(chunk: &Chunk, frame: &Frame) -> usize {
+        chunk.location(frame.ip - 1).map_or(0, |location| location.line)
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn peek(&self) -> &Value {
+        self.stack.last().expect("value stack underflow")
+    }
+}
@@ -1,367 +1,658 @@
-use logos::Logos;
-use std::fmt;
-
-#[derive(Logos, Debug, PartialEq, Clone)]
-pub enum TokenType {
-    // Single-character tokens
-    #[token("(")]
-    LeftParen,
-    #[token(")")]
-    RightParen,
-    #[token("{")]
-    LeftBrace,
-    #[token("}")]
-    RightBrace,
-    #[token("[")]
-    LeftBracket,
-    #[token("]")]
-    RightBracket,
-    #[token(",")]
-    Comma,
-    #[token(".")]
-    Dot,
-    #[token("-")]
-    Minus,
-    #[token("+")]
-    Plus,
-    #[token(";")]
-    Semicolon,
-    #[token("/")]
-    Slash,
-    #[token("*")]
-    Star,
-    #[token("\n")]
-    Newline,
-
-    // One or two character tokens
-    #[token("!")]
-    Bang,
-    #[token("!=")]
-    BangEqual,
-    #[token("=")]
-    Equal,
-    #[token("==")]
-    EqualEqual,
-    #[token(">")]
-    Greater,
-    #[token(">=")]
-    GreaterEqual,
-    #[token("<")]
-    Less,
-    #[token("<=")]
-    LessEqual,
-
-    // Literals
-    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
-    Identifier(String),
-    #[regex(r#""[^"]*""#, |lex| lex.slice()[1..lex.slice().len()-1].to_string())]
-    String(String),
-    #[regex(r"[0-9]+(\.[0-9]+)?", |lex| lex.slice().parse::<f64>().unwrap())]
-    Number(f64),
-
-    // Keywords
-    #[token("and")]
-    And,
-    #[token("class")]
-    Class,
-    #[token("else")]
-    Else,
-    #[token("false")]
-    False,
-    #[token("for")]
-    For,
-    #[token("fun")]
-    Fun,
-    #[token("if")]
-    If,
-    #[token("nil")]
-    Nil,
-    #[token("or")]
-    Or,
-    #[token("print")]
-    Print,
-    #[token("return")]
-    Return,
-    #[token("super")]
-    Super,
-    #[token("this")]
-    This,
-    #[token("true")]
-    True,
-    #[token("var")]
-    Var,
-    #[token("while")]
-    While,
-
-    // Special tokens
-    #[regex(r"[ \t\f]+", logos::skip)]
-    Error,
-    EOF,
-}
-
-#[derive(Debug, Clone)]
-pub struct Token {
-    pub token_type: TokenType,
-    pub lexeme: String,
-    pub literal: Option<String>,
-    pub line: usize,
-    pub column: usize,
-}
-
-impl Token {
-    pub fn new(
-        token_type: TokenType,
-        lexeme: String,
-        literal: Option<String>,
-        line: usize,
-        column: usize,
-    ) -> Self {
-        Token {
-            token_type,
-            lexeme,
-            literal,
-            line,
-            column,
-        }
-    }
-}
-
-impl fmt::Display for Token {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Token {{ type: {:?}, lexeme: {}, literal: {:?}, line: {}, column: {} }}",
-            self.token_type, self.lexeme, self.literal, self.line, self.column
-        )
-    }
-}
-
-pub struct Lexer {
-    source: String,
-    tokens: Vec<Token>,
-    start: usize,
-    current: usize,
-    line: usize,
-    column: usize,
-}
-
-impl Lexer {
-    pub fn new(source: String) -> Self {
-        Lexer {
-            source,
-            tokens: Vec::new(),
-            start: 0,
-            current: 0,
-            line: 1,
-            column: 1,
-        }
-    }
-
-    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, String> {
-        while !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token()?;
-        }
-
-        self.tokens.push(Token::new(
-            TokenType::EOF,
-            "".to_string(),
-            None,
-            self.line,
-            self.column,
-        ));
-
-        Ok(self.tokens.clone())
-    }
-
-    fn scan_token(&mut self) -> Result<(), String> {
-        let c = self.advance();
-        match c {
-            '(' => self.add_token(TokenType::LeftParen),
-            ')' => self.add_token(TokenType::RightParen),
-            '{' => self.add_token(TokenType::LeftBrace),
-            '}' => self.add_token(TokenType::RightBrace),
-            '[' => self.add_token(TokenType::LeftBracket),
-            ']' => self.add_token(TokenType::RightBracket),
-            ',' => self.add_token(TokenType::Comma),
-            '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
-            '+' => self.add_token(TokenType::Plus),
-            ';' => self.add_token(TokenType::Semicolon),
-            '*' => self.add_token(TokenType::Star),
-            '!' => {
-                if self.match_char('=') {
-                    self.add_token(TokenType::BangEqual);
-                } else {
-                    self.add_token(TokenType::Bang);
-                }
-            }
-            '=' => {
-                if self.match_char('=') {
-                    self.add_token(TokenType::EqualEqual);
-                } else {
-                    self.add_token(TokenType::Equal);
-                }
-            }
-            '<' => {
-                if self.match_char('=') {
-                    self.add_token(TokenType::LessEqual);
-                } else {
-                    self.add_token(TokenType::Less);
-                }
-            }
-            '>' => {
-                if self.match_char('=') {
-                    self.add_token(TokenType::GreaterEqual);
-                } else {
-                    self.add_token(TokenType::Greater);
-                }
-            }
-            '/' => {
-                if self.match_char('/') {
-                    // A comment goes until the end of the line
-                    while self.peek() != '\n' && !self.is_at_end() {
-                        self.advance();
-                    }
-                } else {
-                    self.add_token(TokenType::Slash);
-                }
-            }
-            ' ' | '\r' | '\t' => {
-                // Ignore whitespace
-            }
-            '\n' => {
-                self.add_token(TokenType::Newline);
-                self.line += 1;
-                self.column = 1;
-            }
-            '"' => self.string()?,
-            c if c.is_ascii_digit() => self.number()?,
-            c if c.is_ascii_alphabetic() || c == '_' => self.identifier()?,
-            _ => return Err("Unexpected character.".to_string()),
-        }
-        Ok(())
-    }
-
-    fn string(&mut self) -> Result<(), String> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-                self.column = 1;
-            }
-            self.advance();
-        }
-
-        if self.is_at_end() {
-            return Err("Unterminated string.".to_string());
-        }
-
-        // The closing "
-        self.advance();
-
-        // Trim the surrounding quotes
-        let value = self.source[self.start + 1..self.current - 1].to_string();
-        self.add_token_with_literal(TokenType::String(value.clone()), Some(value));
-        Ok(())
-    }
-
-    fn number(&mut self) -> Result<(), String> {
-        while self.peek().is_ascii_digit() {
-            self.advance();
-        }
-
-        // Look for a fractional part
-        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
-            // Consume the "."
-            self.advance();
-
-            while self.peek().is_ascii_digit() {
-                self.advance();
-            }
-        }
-
-        let value = self.source[self.start..self.current].to_string();
-        self.add_token_with_literal(TokenType::Number(value.parse().unwrap()), Some(value));
-        Ok(())
-    }
-
-    fn identifier(&mut self) -> Result<(), String> {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
-            self.advance();
-        }
-
-        let text = &self.source[self.start..self.current];
-        let token_type = match text {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "if" => TokenType::If,
-            "nil" => TokenType::Nil,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "while" => TokenType::While,
-            _ => TokenType::Identifier(text.to_string()),
-        };
-
-        self.add_token(token_type);
-        Ok(())
-    }
-
-    fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source.chars().nth(self.current) != Some(expected) {
-            return false;
-        }
-        self.current += 1;
-        self.column += 1;
-        true
-    }
-
-    fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
-    }
-
-    fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
-    }
-
-    fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
-        self.column += 1;
-        c
-    }
-
-    fn add_token(&mut self, token_type: TokenType) {
-        let text = self.source[self.start..self.current].to_string();
-        self.add_token_with_literal(token_type, Some(text));
-    }
-
-    fn add_token_with_literal(&mut self, token_type: TokenType, literal: Option<String>) {
-        let text = self.source[self.start..self.current].to_string();
-        self.tokens.push(Token::new(
-            token_type,
-            text,
-            literal,
-            self.line,
-            self.column,
-        ));
-    }
-
-    fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
-    }
-}
+use logos::Logos;
+use crate::error::{Diagnostic, Location};
+use std::fmt;
+
+#[derive(Logos, Debug, PartialEq, Clone)]
+pub enum TokenType {
+    // Single-character tokens
+    #[token("(")]
+    LeftParen,
+    #[token(")")]
+    RightParen,
+    #[token("{")]
+    LeftBrace,
+    #[token("}")]
+    RightBrace,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
+    #[token(",")]
+    Comma,
+    #[token(".")]
+    Dot,
+    #[token(":")]
+    Colon,
+    #[token("?")]
+    Question,
+    #[token("-")]
+    Minus,
+    #[token("+")]
+    Plus,
+    #[token(";")]
+    Semicolon,
+    #[token("/")]
+    Slash,
+    #[token("*")]
+    Star,
+    #[token("%")]
+    Percent,
+    #[token("\n")]
+    Newline,
+
+    // One or two character tokens
+    #[token("!")]
+    Bang,
+    #[token("!=")]
+    BangEqual,
+    #[token("=")]
+    Equal,
+    #[token("==")]
+    EqualEqual,
+    #[token("=>")]
+    Arrow,
+    #[token("??")]
+    QuestionQuestion,
+    #[token("?.")]
+    QuestionDot,
+    #[token("**")]
+    StarStar,
+    #[token("~/")]
+    TildeSlash,
+    #[token("+=")]
+    PlusEqual,
+    #[token("-=")]
+    MinusEqual,
+    #[token("*=")]
+    StarEqual,
+    #[token("/=")]
+    SlashEqual,
+    #[token("%=")]
+    PercentEqual,
+    #[token("++")]
+    PlusPlus,
+    #[token("--")]
+    MinusMinus,
+    #[token(">")]
+    Greater,
+    #[token(">=")]
+    GreaterEqual,
+    #[token("<")]
+    Less,
+    #[token("<=")]
+    LessEqual,
+
+    // Literals
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
+    Identifier(String),
+    #[regex(r#""[^"]*""#, |lex| lex.slice()[1..lex.slice().len()-1].to_string())]
+    String(String),
+    // Pieces of a template literal around its `${...}` expressions
+    TemplateStart(String),
+    TemplateMiddle(String),
+    TemplateEnd(String),
+    #[regex(r"[0-9]+(\.[0-9]+)?", |lex| lex.slice().parse::<f64>().unwrap())]
+    Number(f64),
+
+    // Keywords
+    #[token("and")]
+    #[token("&&")]
+    And,
+    #[token("break")]
+    Break,
+    #[token("catch")]
+    Catch,
+    #[token("class")]
+    Class,
+    #[token("continue")]
+    Continue,
+    #[token("else")]
+    Else,
+    #[token("false")]
+    False,
+    #[token("finally")]
+    Finally,
+    #[token("for")]
+    For,
+    #[token("fun")]
+    Fun,
+    #[token("if")]
+    If,
+    #[token("in")]
+    In,
+    #[token("nil")]
+    Nil,
+    #[token("or")]
+    #[token("||")]
+    Or,
+    #[token("print")]
+    Print,
+    #[token("return")]
+    Return,
+    #[token("super")]
+    Super,
+    #[token("this")]
+    This,
+    #[token("throw")]
+    Throw,
+    #[token("true")]
+    True,
+    #[token("try")]
+    Try,
+    #[token("var")]
+    Var,
+    #[token("while")]
+    While,
+
+    // Special tokens
+    #[regex(r"[ \t\f]+", logos::skip)]
+    Error,
+    EOF,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub token_type: TokenType,
+    pub lexeme: String,
+    pub literal: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    pub fn location(&self) -> Location {
+        Location::new(self.line, self.column)
+    }
+
+    pub fn new(
+        token_type: TokenType,
+        lexeme: String,
+        literal: Option<String>,
+        line: usize,
+        column: usize,
+    ) -> Self {
+        Token {
+            token_type,
+            lexeme,
+            literal,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Token {{ type: {:?}, lexeme: {}, literal: {:?}, line: {}, column: {} }}",
+            self.token_type, self.lexeme, self.literal, self.line, self.column
+        )
+    }
+}
+
+pub struct Lexer {
+    source: String,
+    tokens: Vec<Token>,
+    start: usize,
+    current: usize,
+    line: usize,
+    column: usize,
+    // Where the token being scanned starts
+    start_line: usize,
+    start_column: usize,
+    // Open brace count inside each `${...}` being scanned, innermost last
+    template_braces: Vec<usize>,
+}
+
+impl Lexer {
+    pub fn new(source: String) -> Self {
+        Lexer {
+            source,
+            tokens: Vec::new(),
+            start: 0,
+            current: 0,
+            line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            template_braces: Vec::new(),
+        }
+    }
+
+    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Diagnostic> {
+        while !self.is_at_end() {
+            self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
+            self.scan_token()?;
+        }
+
+        if !self.template_braces.is_empty() {
+            return Err(Diagnostic::at("Unterminated template literal.", self.line, self.column));
+        }
+
+        self.tokens.push(Token::new(
+            TokenType::EOF,
+            "".to_string(),
+            None,
+            self.line,
+            self.column,
+        ));
+
+        Ok(self.tokens.clone())
+    }
+
+    fn scan_token(&mut self) -> Result<(), Diagnostic> {
+        let c = self.advance();
+        match c {
+            '(' => self.add_token(TokenType::LeftParen),
+            ')' => self.add_token(TokenType::RightParen),
+            '{' => {
+                if let Some(depth) = self.template_braces.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(TokenType::LeftBrace);
+            }
+            '}' => match self.template_braces.last_mut() {
+                Some(0) => {
+                    // Closes a `${...}`, so resume scanning the template text
+                    self.template_braces.pop();
+                    self.template(false)?;
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(TokenType::RightBrace);
+                }
+                None => self.add_token(TokenType::RightBrace),
+            },
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
+            ',' => self.add_token(TokenType::Comma),
+            // A number may start with its fractional part, e.g. `.5`
+            '.' if self.peek().is_ascii_digit() => self.number()?,
+            '.' => self.add_token(TokenType::Dot),
+            ':' => self.add_token(TokenType::Colon),
+            '?' => {
+                if self.match_char('?') {
+                    self.add_token(TokenType::QuestionQuestion);
+                } else if self.peek() == '.' && !self.peek_next().is_ascii_digit() {
+                    // `c ?.5 : 1` is a conditional with a number, as in JavaScript
+                    self.advance();
+                    self.add_token(TokenType::QuestionDot);
+                } else {
+                    self.add_token(TokenType::Question);
+                }
+            }
+            '-' => {
+                if self.match_char('-') {
+                    self.add_token(TokenType::MinusMinus);
+                } else if self.match_char('=') {
+                    self.add_token(TokenType::MinusEqual);
+                } else {
+                    self.add_token(TokenType::Minus);
+                }
+            }
+            '+' => {
+                if self.match_char('+') {
+                    self.add_token(TokenType::PlusPlus);
+                } else if self.match_char('=') {
+                    self.add_token(TokenType::PlusEqual);
+                } else {
+                    self.add_token(TokenType::Plus);
+                }
+            }
+            ';' => self.add_token(TokenType::Semicolon),
+            '%' => {
+                if self.match_char('=') {
+                    self.add_token(TokenType::PercentEqual);
+                } else {
+                    self.add_token(TokenType::Percent);
+                }
+            }
+            '*' => {
+                if self.match_char('*') {
+                    self.add_token(TokenType::StarStar);
+                } else if self.match_char('=') {
+                    self.add_token(TokenType::StarEqual);
+                } else {
+                    self.add_token(TokenType::Star);
+                }
+            }
+            '~' => {
+                if self.match_char('/') {
+                    self.add_token(TokenType::TildeSlash);
+                } else {
+                    return Err(self.error("Unexpected character '~'. Did you mean '~/'?"));
+                }
+            }
+            '!' => {
+                if self.match_char('=') {
+                    self.add_token(TokenType::BangEqual);
+                } else {
+                    self.add_token(TokenType::Bang);
+                }
+            }
+            '=' => {
+                if self.match_char('=') {
+                    self.add_token(TokenType::EqualEqual);
+                } else if self.match_char('>') {
+                    self.add_token(TokenType::Arrow);
+                } else {
+                    self.add_token(TokenType::Equal);
+                }
+            }
+            '<' => {
+                if self.match_char('=') {
+                    self.add_token(TokenType::LessEqual);
+                } else {
+                    self.add_token(TokenType::Less);
+                }
+            }
+            '>' => {
+                if self.match_char('=') {
+                    self.add_token(TokenType::GreaterEqual);
+                } else {
+                    self.add_token(TokenType::Greater);
+                }
+            }
+            '&' => {
+                if self.match_char('&') {
+                    self.add_token(TokenType::And);
+                } else {
+                    return Err(self.error("Unexpected character '&'. Did you mean '&&'?"));
+                }
+            }
+            '|' => {
+                if self.match_char('|') {
+                    self.add_token(TokenType::Or);
+                } else {
+                    return Err(self.error("Unexpected character '|'. Did you mean '||'?"));
+                }
+            }
+            '/' => {
+                if self.match_char('/') {
+                    // A comment goes until the end of the line
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                } else if self.match_char('=') {
+                    self.add_token(TokenType::SlashEqual);
+                } else {
+                    self.add_token(TokenType::Slash);
+                }
+            }
+            ' ' | '\r' | '\t' => {
+                // Ignore whitespace
+            }
+            '\n' => {
+                self.add_token(TokenType::Newline);
+                self.line += 1;
+                self.column = 1;
+            }
+            '"' => self.string()?,
+            '`' => self.template(true)?,
+            'r' if self.peek() == '"' => self.raw_string()?,
+            c if c.is_ascii_digit() => self.number()?,
+            c if c.is_ascii_alphabetic() || c == '_' => self.identifier()?,
+            c => return Err(self.error(format!("Unexpected character '{}'.", c))),
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<(), Diagnostic> {
+        let mut value = String::new();
+        while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '\n' {
+                // `advance` moves past the newline to column 1
+                self.line += 1;
+                self.column = 0;
+            }
+            if self.peek() == '\\' {
+                value.push(self.escape_sequence()?);
+            } else {
+                value.push(self.advance());
+            }
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("Unterminated string."));
+        }
+
+        // The closing "
+        self.advance();
+
+        self.add_token_with_literal(TokenType::String(value.clone()), Some(value));
+        Ok(())
+    }
+
+    /// Decode the escape sequence starting at the current backslash.
+    fn escape_sequence(&mut self) -> Result<char, Diagnostic> {
+        let (line, column) = (self.line, self.column);
+        self.advance();
+        if self.is_at_end() {
+            return Err(self.error("Unterminated string."));
+        }
+
+        let escaped = match self.advance() {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            '`' => '`',
+            '$' => '$',
+            'u' => return self.unicode_escape(line, column),
+            c => return Err(Diagnostic::at(format!("Invalid escape sequence '\\{}'.", c), line, column)),
+        };
+        Ok(escaped)
+    }
+
+    /// Scan template text up to the closing backtick or the next `${`. The
+    /// expression tokens inside `${...}` are scanned as usual, and the `}`
+    /// that closes them calls back in here with `opening` false.
+    fn template(&mut self, opening: bool) -> Result<(), Diagnostic> {
+        let mut text = String::new();
+        loop {
+            if self.is_at_end() {
+                return Err(self.error("Unterminated template literal."));
+            }
+            match self.peek() {
+                '`' => {
+                    self.advance();
+                    let token_type = if opening {
+                        TokenType::String(text.clone())
+                    } else {
+                        TokenType::TemplateEnd(text.clone())
+                    };
+                    self.add_token_with_literal(token_type, Some(text));
+                    return Ok(());
+                }
+                '$' if self.peek_next() == '{' => {
+                    self.advance();
+                    self.advance();
+                    self.template_braces.push(0);
+                    let token_type = if opening {
+                        TokenType::TemplateStart(text.clone())
+                    } else {
+                        TokenType::TemplateMiddle(text.clone())
+                    };
+                    self.add_token_with_literal(token_type, Some(text));
+                    return Ok(());
+                }
+                '\\' => text.push(self.escape_sequence()?),
+                '\n' => {
+                    self.line += 1;
+                    self.column = 0;
+                    text.push(self.advance());
+                }
+                _ => text.push(self.advance()),
+            }
+        }
+    }
+
+    /// Decode the `{XXXX}` part of a `\u{XXXX}` escape.
+    fn unicode_escape(&mut self, line: usize, column: usize) -> Result<char, Diagnostic> {
+        let invalid = |reason: &str| Err(Diagnostic::at(format!("Invalid unicode escape: {}.", reason), line, column));
+
+        if !self.match_char('{') {
+            return invalid("expected '{' after '\\u'");
+        }
+        let mut digits = String::new();
+        while self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+        if !self.match_char('}') {
+            return invalid("expected hex digits followed by '}'");
+        }
+        if digits.is_empty() || digits.len() > 6 {
+            return invalid("expected 1 to 6 hex digits");
+        }
+
+        match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => invalid(&format!("'{}' is not a valid code point", digits)),
+        }
+    }
+
+    /// Raw strings (`r"..."`) keep backslashes verbatim, which suits regex
+    /// patterns and Windows paths.
+    fn raw_string(&mut self) -> Result<(), Diagnostic> {
+        // The opening "
+        self.advance();
+        let mut value = String::new();
+        while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '\n' {
+                // `advance` moves past the newline to column 1
+                self.line += 1;
+                self.column = 0;
+            }
+            value.push(self.advance());
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("Unterminated raw string."));
+        }
+
+        // The closing "
+        self.advance();
+
+        self.add_token_with_literal(TokenType::String(value.clone()), Some(value));
+        Ok(())
+    }
+
+    fn number(&mut self) -> Result<(), Diagnostic> {
+        while self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        // Look for a fractional part, unless the number began with one
+        let started_with_dot = self.source[self.start..].starts_with('.');
+        if !started_with_dot && self.peek() == '.' && self.peek_next().is_ascii_digit() {
+            // Consume the "."
+            self.advance();
+
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        }
+
+        let value = self.source[self.start..self.current].to_string();
+        self.add_token_with_literal(TokenType::Number(value.parse().unwrap()), Some(value));
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<(), Diagnostic> {
+        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+
+        let text = &self.source[self.start..self.current];
+        let token_type = match text {
+            "and" => TokenType::And,
+            "break" => TokenType::Break,
+            "catch" => TokenType::Catch,
+            "class" => TokenType::Class,
+            "continue" => TokenType::Continue,
+            "else" => TokenType::Else,
+            "false" => TokenType::False,
+            "finally" => TokenType::Finally,
+            "for" => TokenType::For,
+            "fun" => TokenType::Fun,
+            "if" => TokenType::If,
+            "in" => TokenType::In,
+            "nil" => TokenType::Nil,
+            "or" => TokenType::Or,
+            "print" => TokenType::Print,
+            "return" => TokenType::Return,
+            "super" => TokenType::Super,
+            "this" => TokenType::This,
+            "throw" => TokenType::Throw,
+            "true" => TokenType::True,
+            "try" => TokenType::Try,
+            "var" => TokenType::Var,
+            "while" => TokenType::While,
+            _ => TokenType::Identifier(text.to_string()),
+        };
+
+        self.add_token(token_type);
+        Ok(())
+    }
+
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.source.chars().nth(self.current) != Some(expected) {
+            return false;
+        }
+        self.current += 1;
+        self.column += 1;
+        true
+    }
+
+    fn peek(&self) -> char {
+        if self.is_at_end() {
+            return '\0';
+        }
+        self.source.chars().nth(self.current).unwrap()
+    }
+
+    fn peek_next(&self) -> char {
+        if self.current + 1 >= self.source.len() {
+            return '\0';
+        }
+        self.source.chars().nth(self.current + 1).unwrap()
+    }
+
+    fn advance(&mut self) -> char {
+        let c = self.source.chars().nth(self.current).unwrap();
+        self.current += 1;
+        self.column += 1;
+        c
+    }
+
+    fn add_token(&mut self, token_type: TokenType) {
+        let text = self.source[self.start..self.current].to_string();
+        self.add_token_with_literal(token_type, Some(text));
+    }
+
+    fn add_token_with_literal(&mut self, token_type: TokenType, literal: Option<String>) {
+        let text = self.source[self.start..self.current].to_string();
+        self.tokens.push(Token::new(
+            token_type,
+            text,
+            literal,
+            self.start_line,
+            self.start_column,
+        ));
+    }
+
+    /// An error located at the start of the token being scanned.
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::at(message, self.start_line, self.start_column)
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+}
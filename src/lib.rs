@@ -1,9 +1,23 @@
+pub mod error;
 pub mod lexer;
 pub mod ast_parser;
+pub mod resolver;
+pub mod compiler;
 pub mod interpreter;
 pub mod stdlib;
 
+pub use error::{CallFrame, Diagnostic, Error, Location};
 pub use lexer::{Lexer, Token, TokenType};
-pub use ast_parser::{Parser, Expr, Stmt, Literal};
-pub use interpreter::{Interpreter, Value};
-pub use stdlib::StdLib;
+pub use ast_parser::{Parser, Expr, Stmt, Literal, Binding};
+pub use resolver::Resolver;
+pub use interpreter::{Backend, Interpreter, Value};
+pub use stdlib::{Namespace, StdLib, StdLibBuilder};
+
+/// Lex, parse, resolve and interpret `source` on `interpreter`, stopping at
+/// the first step that fails.
+pub fn run(interpreter: &mut Interpreter, source: &str) -> Result<(), Error> {
+    let tokens = Lexer::new(source.to_string()).scan_tokens().map_err(Error::Lexical)?;
+    let mut statements = Parser::new(tokens).parse().map_err(Error::Syntax)?;
+    Resolver::new().resolve(&mut statements).map_err(Error::Resolution)?;
+    interpreter.interpret(statements).map_err(Error::Runtime)
+}
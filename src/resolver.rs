@@ -0,0 +1,404 @@
+//! Static resolution pass run on a parsed program before it is interpreted.
+//!
+//! Every local variable is numbered by its position in the scope that
+//! declares it, and each use of a local is bound to a (depth, slot) pair so
+//! the interpreter can reach it without looking it up by name. Names that are
+//! not found in any enclosing local scope are globals, which stay late-bound
+//! by name. The same walk reports mistakes that can be seen without running
+//! the program.
+
+use crate::ast_parser::{Binding, Expr, Stmt};
+use crate::error::Diagnostic;
+use crate::lexer::Token;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // Declared further down its scope, so only closures can refer to it yet
+    Pending,
+    // Its initializer is being resolved
+    Declared,
+    Defined,
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    state: State,
+}
+
+#[derive(Debug)]
+struct Scope {
+    // In slot order, the order the interpreter defines them in
+    locals: Vec<Local>,
+    // The `function_depth` of the code running in this scope
+    function_depth: usize,
+}
+
+pub struct Resolver {
+    // Local scopes enclosing the code being resolved; empty at the top level
+    scopes: Vec<Scope>,
+    // Number of function bodies enclosing the code being resolved
+    function_depth: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver {
+            scopes: Vec::new(),
+            function_depth: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Bind the variables in `statements` to their slots, returning every
+    /// error found if there were any.
+    pub fn resolve(&mut self, statements: &mut [Stmt]) -> Result<(), Vec<Diagnostic>> {
+        for statement in statements.iter_mut() {
+            self.resolve_stmt(statement);
+        }
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.diagnostics))
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) | Stmt::Throw(_, expr) => self.resolve_expr(expr),
+            Stmt::Var(name, initializer) => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.resolve_expr(initializer);
+                }
+                self.define(name);
+            }
+            Stmt::Block(statements) => self.resolve_scope(&[], statements),
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch);
+                }
+            }
+            Stmt::While(condition, body) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(body);
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.reserve(std::slice::from_ref(&**initializer));
+                    self.resolve_stmt(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.resolve_expr(condition);
+                }
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment);
+                }
+                self.resolve_stmt(body);
+                self.scopes.pop();
+            }
+            Stmt::ForIn(index, item, iterable, body) => {
+                self.resolve_expr(iterable);
+                let names: Vec<&Token> = index.iter().chain(std::iter::once(&*item)).collect();
+                self.begin_scope();
+                for name in names {
+                    self.bind(name);
+                }
+                self.resolve_stmt(body);
+                self.scopes.pop();
+            }
+            Stmt::Function(name, params, body) => {
+                // Defined before its body so the function can call itself
+                self.declare(name);
+                self.define(name);
+                self.resolve_function(params, body);
+            }
+            Stmt::Class(name, superclass, methods) => {
+                if let Some(superclass) = superclass {
+                    self.resolve_expr(superclass);
+                }
+                self.declare(name);
+                self.define(name);
+
+                // Methods close over a scope binding `super` when there is a
+                // superclass, and each bound method over one binding `this`
+                if superclass.is_some() {
+                    self.begin_scope();
+                    self.bind_keyword("super");
+                }
+                for method in methods.iter_mut() {
+                    if let Stmt::Function(_, params, body) = method {
+                        self.begin_scope();
+                        self.bind_keyword("this");
+                        self.resolve_function(params, body);
+                        self.scopes.pop();
+                    }
+                }
+                if superclass.is_some() {
+                    self.scopes.pop();
+                }
+            }
+            Stmt::Return(keyword, value) => {
+                if self.function_depth == 0 {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    self.resolve_expr(value);
+                }
+            }
+            Stmt::Break(_) | Stmt::Continue(_) => {}
+            Stmt::Try(body, catch, finally) => {
+                self.resolve_scope(&[], body);
+                if let Some((name, handler)) = catch {
+                    self.resolve_scope(std::slice::from_ref(name), handler);
+                }
+                if let Some(finally) = finally {
+                    self.resolve_scope(&[], finally);
+                }
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Variable(name, binding) => *binding = self.resolve_local(name, true),
+            Expr::Assign(name, value, binding) => {
+                self.resolve_expr(value);
+                *binding = self.resolve_local(name, false);
+            }
+            Expr::This(keyword, binding) => *binding = self.resolve_local(keyword, false),
+            Expr::Super(keyword, _, binding) => *binding = self.resolve_local(keyword, false),
+            Expr::Function(_, params, body) => {
+                // The parser hands over the only reference, so this does not copy
+                let body: &mut Vec<Stmt> = Rc::make_mut(body);
+                self.resolve_function(params, body);
+            }
+            Expr::Binary(left, _, right)
+            | Expr::Logical(left, _, right)
+            | Expr::Index(left, _, right)
+            | Expr::Compound(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Grouping(expr)
+            | Expr::Unary(_, expr)
+            | Expr::Get(expr, _)
+            | Expr::Update(expr, _, _)
+            | Expr::Optional(expr)
+            | Expr::Chain(expr) => self.resolve_expr(expr),
+            Expr::Call(callee, _, arguments) | Expr::Method(callee, _, arguments) => {
+                self.resolve_expr(callee);
+                for argument in arguments {
+                    self.resolve_expr(argument);
+                }
+            }
+            Expr::Array(elements) | Expr::Template(elements) => {
+                for element in elements {
+                    self.resolve_expr(element);
+                }
+            }
+            Expr::Object(properties) => {
+                for (_, value) in properties {
+                    self.resolve_expr(value);
+                }
+            }
+            Expr::IndexSet(object, _, index, value) => {
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+                self.resolve_expr(value);
+            }
+            Expr::Set(object, _, value) => {
+                self.resolve_expr(object);
+                self.resolve_expr(value);
+            }
+            Expr::Conditional(condition, _, then_branch, else_branch) => {
+                self.resolve_expr(condition);
+                self.resolve_expr(then_branch);
+                self.resolve_expr(else_branch);
+            }
+            Expr::Literal(_) => {}
+        }
+    }
+
+    /// Resolve a function body, which runs in one scope holding the parameters
+    /// followed by the body's own declarations.
+    fn resolve_function(&mut self, params: &[Token], body: &mut [Stmt]) {
+        self.function_depth += 1;
+        self.resolve_scope(params, body);
+        self.function_depth -= 1;
+    }
+
+    /// Resolve `statements` in a new scope whose first slots hold `bound`,
+    /// variables the interpreter defines before running them.
+    fn resolve_scope(&mut self, bound: &[Token], statements: &mut [Stmt]) {
+        self.begin_scope();
+        for name in bound {
+            self.bind(name);
+        }
+        self.reserve(statements);
+        for statement in statements.iter_mut() {
+            self.resolve_stmt(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope {
+            locals: Vec::new(),
+            function_depth: self.function_depth,
+        });
+    }
+
+    /// Give every declaration among `statements` its slot up front, so that
+    /// functions in the scope can refer to locals declared after them.
+    fn reserve(&mut self, statements: &[Stmt]) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+        for statement in statements {
+            if let Stmt::Var(name, _) | Stmt::Function(name, _, _) | Stmt::Class(name, _, _) = statement {
+                scope.locals.push(Local {
+                    name: name.lexeme.clone(),
+                    state: State::Pending,
+                });
+            }
+        }
+    }
+
+    /// Add a variable that is defined as soon as its scope is entered.
+    fn bind(&mut self, name: &Token) {
+        if self.is_declared(&name.lexeme) {
+            self.error(name, format!("Variable '{}' is already declared in this scope.", name.lexeme));
+        }
+        self.bind_keyword(&name.lexeme);
+    }
+
+    fn bind_keyword(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.locals.push(Local {
+                name: name.to_string(),
+                state: State::Defined,
+            });
+        }
+    }
+
+    /// Mark the declaration of `name` as reached. Top-level declarations are
+    /// globals, which may be declared again.
+    fn declare(&mut self, name: &Token) {
+        if self.scopes.is_empty() {
+            return;
+        }
+        if self.is_declared(&name.lexeme) {
+            self.error(name, format!("Variable '{}' is already declared in this scope.", name.lexeme));
+        }
+        self.set_state(&name.lexeme, State::Pending, State::Declared);
+    }
+
+    fn define(&mut self, name: &Token) {
+        self.set_state(&name.lexeme, State::Declared, State::Defined);
+    }
+
+    /// Whether the innermost scope already has a reachable variable `name`.
+    fn is_declared(&self, name: &str) -> bool {
+        self.scopes.last().is_some_and(|scope| {
+            scope.locals.iter().any(|local| local.name == name && local.state != State::Pending)
+        })
+    }
+
+    /// Move the first variable `name` in state `from` in the innermost scope
+    /// to state `to`.
+    fn set_state(&mut self, name: &str, from: State, to: State) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+        match scope.locals.iter_mut().find(|local| local.name == name && local.state == from) {
+            Some(local) => local.state = to,
+            // Declarations are reserved when their scope is entered
+            None => unreachable!("variable '{}' was not reserved", name),
+        }
+    }
+
+    /// Find the local `name` refers to, or `None` for a global. Code sees the
+    /// locals of its own function once they are declared; a closure also sees
+    /// those its enclosing functions declare later on.
+    fn resolve_local(&mut self, name: &Token, reading: bool) -> Option<Binding> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            let same_function = scope.function_depth == self.function_depth;
+            let found = scope
+                .locals
+                .iter()
+                .rposition(|local| local.name == name.lexeme && (!same_function || local.state != State::Pending));
+            if let Some(slot) = found {
+                if reading && same_function && scope.locals[slot].state == State::Declared {
+                    self.error(name, "Can't read local variable in its own initializer.");
+                }
+                return Some(Binding { depth, slot });
+            }
+        }
+        None
+    }
+
+    fn error(&mut self, token: &Token, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic::at(message, token.line, token.column));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_parser::Parser;
+    use crate::lexer::Lexer;
+
+    fn resolve(source: &str) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let tokens = Lexer::new(source.to_string()).scan_tokens().unwrap();
+        let mut statements = Parser::new(tokens).parse_partial().0;
+        Resolver::new().resolve(&mut statements)?;
+        Ok(statements)
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        resolve(source).unwrap_err().into_iter().map(|error| error.message).collect()
+    }
+
+    #[test]
+    fn test_binds_locals_to_depth_and_slot() {
+        let statements = resolve("var g = 1; { var a = 1; var b = 2; { print b + g; } }").unwrap();
+        let Stmt::Block(outer) = &statements[1] else { panic!("expected a block") };
+        let Stmt::Block(inner) = &outer[2] else { panic!("expected a block") };
+        let Stmt::Print(Expr::Binary(left, _, right)) = &inner[0] else { panic!("expected a print") };
+        assert!(matches!(**left, Expr::Variable(_, Some(Binding { depth: 1, slot: 1 }))));
+        assert!(matches!(**right, Expr::Variable(_, None)));
+    }
+
+    #[test]
+    fn test_closures_see_later_declarations() {
+        let statements = resolve("fun f() { fun even(n) { return odd(n); } fun odd(n) { return n; } }").unwrap();
+        let Stmt::Function(_, _, body) = &statements[0] else { panic!("expected a function") };
+        let Stmt::Function(_, _, even) = &body[0] else { panic!("expected a function") };
+        let Stmt::Return(_, Some(Expr::Call(callee, _, _))) = &even[0] else { panic!("expected a return") };
+        assert!(matches!(**callee, Expr::Variable(_, Some(Binding { depth: 1, slot: 1 }))));
+    }
+
+    #[test]
+    fn test_reports_compile_time_errors() {
+        assert_eq!(messages("{ var a = a; }"), ["Can't read local variable in its own initializer."]);
+        assert_eq!(messages("{ var a = 1; var a = 2; }"), ["Variable 'a' is already declared in this scope."]);
+        assert_eq!(messages("fun f(a, a) {}"), ["Variable 'a' is already declared in this scope."]);
+        assert_eq!(messages("return 1;"), ["Can't return from top-level code."]);
+        // Globals are late-bound and may be declared again
+        assert!(resolve("var a = 1; var a = a + 1;").is_ok());
+        // A closure may refer to the variable it initializes
+        assert!(resolve("{ var f = fun () { return f; }; }").is_ok());
+    }
+}
@@ -1,43 +1,58 @@
-use crate::interpreter::Value;
+use crate::interpreter::{NativeFunction, Value};
+use std::rc::Rc;
 
 /// Create a new array: array(item1, item2, ...)
 pub fn array(args: Vec<Value>) -> Result<Value, String> {
     // We allow variable number of arguments here
-    Ok(Value::Array(args))
+    Ok(Value::array(args))
+}
+
+/// Look up a method callable on array values, e.g. `numbers.push(4)`. The
+/// array is passed as the function's first argument.
+pub fn method(name: &str) -> Option<NativeFunction> {
+    let method = |arity, function: fn(Vec<Value>) -> Result<Value, String>| NativeFunction {
+        name: format!("Array.{}", name),
+        arity,
+        function: Rc::new(function),
+    };
+    match name {
+        "push" => Some(method(2, push)),
+        "pop" => Some(method(1, pop)),
+        "get" => Some(method(2, get)),
+        "set" => Some(method(3, set)),
+        "concat" => Some(method(2, concat)),
+        "join" => Some(method(2, join)),
+        "slice" => Some(method(0, slice)),
+        "clone" => Some(method(1, clone)),
+        _ => None,
+    }
 }
 
 /// Get array or string length: length(array)
 pub fn length(args: Vec<Value>) -> Result<Value, String> {
     match &args[0] {
-        Value::Array(elements) => Ok(Value::Number(elements.len() as f64)),
+        Value::Array(elements) => Ok(Value::Number(elements.borrow().len() as f64)),
         Value::String(s) => Ok(Value::Number(s.len() as f64)), // Also works for strings for compatibility
         _ => Err("length: argument must be an array or string".to_string()),
     }
 }
 
-/// Push item to array: push(array, item)
+/// Append item to the array in place and return the array: push(array, item)
 pub fn push(args: Vec<Value>) -> Result<Value, String> {
     match &args[0] {
         Value::Array(elements) => {
-            let mut new_elements = elements.clone();
-            new_elements.push(args[1].clone());
-            Ok(Value::Array(new_elements))
+            elements.borrow_mut().push(args[1].clone());
+            Ok(args[0].clone())
         },
         _ => Err("push: first argument must be an array".to_string()),
     }
 }
 
-/// Pop item from array: pop(array)
+/// Remove and return the last item of the array: pop(array)
 pub fn pop(args: Vec<Value>) -> Result<Value, String> {
     match &args[0] {
         Value::Array(elements) => {
-            let mut new_elements = elements.clone();
-            if new_elements.is_empty() {
-                Err("pop: cannot pop from empty array".to_string())
-            } else {
-                let popped = new_elements.pop().unwrap();
-                Ok(popped)
-            }
+            elements.borrow_mut().pop().ok_or_else(|| "pop: cannot pop from empty array".to_string())
         },
         _ => Err("pop: argument must be an array".to_string()),
     }
@@ -47,6 +62,7 @@ pub fn pop(args: Vec<Value>) -> Result<Value, String> {
 pub fn get(args: Vec<Value>) -> Result<Value, String> {
     match (&args[0], &args[1]) {
         (Value::Array(elements), Value::Number(index)) => {
+            let elements = elements.borrow();
             let idx = *index as usize;
             if idx < elements.len() {
                 Ok(elements[idx].clone())
@@ -70,15 +86,16 @@ pub fn get(args: Vec<Value>) -> Result<Value, String> {
     }
 }
 
-/// Set element at index: set(array, index, value)
+/// Set element at index: set(array, index, value). Arrays are updated in
+/// place and returned; strings are immutable, so a new string is returned.
 pub fn set(args: Vec<Value>) -> Result<Value, String> {
     match (&args[0], &args[1]) {
         (Value::Array(elements), Value::Number(index)) => {
+            let mut elements = elements.borrow_mut();
             let idx = *index as usize;
             if idx < elements.len() {
-                let mut new_elements = elements.clone();
-                new_elements[idx] = args[2].clone();
-                Ok(Value::Array(new_elements))
+                elements[idx] = args[2].clone();
+                Ok(args[0].clone())
             } else {
                 Err(format!("set: index {} out of bounds (array length: {})", idx, elements.len()))
             }
@@ -112,16 +129,14 @@ pub fn set(args: Vec<Value>) -> Result<Value, String> {
 }
 
 /// Access element with bracket notation: index(array, index)
-/// This provides a more intuitive syntax through the function call mechanism:
-/// array[5] becomes "index(array, 5)" in the parsed tree
+/// The interpreter evaluates `array[5]` on arrays and strings through this function
 pub fn index(args: Vec<Value>) -> Result<Value, String> {
     // Simply call get with the same arguments
     get(args)
 }
 
 /// Set element with bracket notation: index_set(array, index, value)
-/// This provides a more intuitive syntax:
-/// array[5] = "hello" becomes "index_set(array, 5, "hello")" in the parsed tree
+/// The interpreter evaluates `array[5] = "hello"` on arrays and strings through this function
 pub fn index_set(args: Vec<Value>) -> Result<Value, String> {
     // Simply call set with the same arguments
     set(args)
@@ -131,9 +146,9 @@ pub fn index_set(args: Vec<Value>) -> Result<Value, String> {
 pub fn concat(args: Vec<Value>) -> Result<Value, String> {
     match (&args[0], &args[1]) {
         (Value::Array(elements1), Value::Array(elements2)) => {
-            let mut result = elements1.clone();
-            result.extend(elements2.clone());
-            Ok(Value::Array(result))
+            let mut result = elements1.borrow().clone();
+            result.extend(elements2.borrow().iter().cloned());
+            Ok(Value::array(result))
         },
         (Value::Array(_), _) => Err("concat: second argument must be an array".to_string()),
         _ => Err("concat: first argument must be an array".to_string()),
@@ -145,7 +160,7 @@ pub fn join(args: Vec<Value>) -> Result<Value, String> {
     match (&args[0], &args[1]) {
         (Value::Array(elements), Value::String(separator)) => {
             let mut result = String::new();
-            for (i, value) in elements.iter().enumerate() {
+            for (i, value) in elements.borrow().iter().enumerate() {
                 if i > 0 {
                     result.push_str(separator);
                 }
@@ -166,6 +181,8 @@ pub fn join(args: Vec<Value>) -> Result<Value, String> {
                     Value::Array(_) => result.push_str("[array]"),
                     Value::Object(_) => result.push_str("{object}"),
                     Value::Namespace(name, _) => result.push_str(&format!("[Namespace: {}]", name)),
+                    Value::Class(class) => result.push_str(&format!("<class {}>", class.name)),
+                    Value::Instance(instance) => result.push_str(&format!("<{} instance>", instance.borrow().class.name)),
                 }
             }
             Ok(Value::String(result))
@@ -193,6 +210,7 @@ pub fn slice(args: Vec<Value>) -> Result<Value, String> {
 
     match (&args[0], &args[1]) {
         (Value::Array(elements), Value::Number(start_idx)) => {
+            let elements = elements.borrow();
             let start = *start_idx as usize;
             if start > elements.len() {
                 return Err(format!("slice: start index {} out of bounds (array length: {})", start, elements.len()));
@@ -202,7 +220,7 @@ pub fn slice(args: Vec<Value>) -> Result<Value, String> {
             let end = std::cmp::min(start + length, elements.len());
 
             let result = elements[start..end].to_vec();
-            Ok(Value::Array(result))
+            Ok(Value::array(result))
         },
         (Value::String(s), Value::Number(start_idx)) => {
             let start = *start_idx as usize;
@@ -222,6 +240,14 @@ pub fn slice(args: Vec<Value>) -> Result<Value, String> {
     }
 }
 
+/// Copy an array or object together with everything nested in it: clone(value)
+pub fn clone(args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Array(_) | Value::Object(_) => Ok(args[0].deep_clone()),
+        _ => Err("clone: argument must be an array or object".to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,6 +262,7 @@ mod tests {
         let result = array(args).unwrap();
 
         if let Value::Array(elements) = result {
+            let elements = elements.borrow();
             assert_eq!(elements.len(), 3);
             assert!(matches!(elements[0], Value::Number(1.0)));
             assert!(matches!(elements[1], Value::Number(2.0)));
@@ -249,7 +276,7 @@ mod tests {
     fn test_length() {
         // Test array length
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
                 Value::Number(3.0),
@@ -269,7 +296,7 @@ mod tests {
     #[test]
     fn test_push() {
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
             ]),
@@ -278,6 +305,7 @@ mod tests {
         let result = push(args).unwrap();
 
         if let Value::Array(elements) = result {
+            let elements = elements.borrow();
             assert_eq!(elements.len(), 3);
             assert!(matches!(elements[2], Value::Number(3.0)));
         } else {
@@ -288,7 +316,7 @@ mod tests {
     #[test]
     fn test_pop() {
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
                 Value::Number(3.0),
@@ -298,11 +326,33 @@ mod tests {
         assert!(matches!(result, Value::Number(3.0)));
     }
 
+    #[test]
+    fn test_push_pop_and_set_update_in_place() {
+        let numbers = Value::array(vec![Value::Number(1.0)]);
+        push(vec![numbers.clone(), Value::Number(2.0)]).unwrap();
+        set(vec![numbers.clone(), Value::Number(0.0), Value::Number(10.0)]).unwrap();
+        assert_eq!(numbers, Value::array(vec![Value::Number(10.0), Value::Number(2.0)]));
+
+        assert_eq!(pop(vec![numbers.clone()]).unwrap(), Value::Number(2.0));
+        assert_eq!(numbers, Value::array(vec![Value::Number(10.0)]));
+    }
+
+    #[test]
+    fn test_clone_is_deep() {
+        let inner = Value::array(vec![Value::Number(1.0)]);
+        let outer = Value::array(vec![inner.clone()]);
+        let copy = clone(vec![outer.clone()]).unwrap();
+        push(vec![inner, Value::Number(2.0)]).unwrap();
+
+        assert_eq!(copy, Value::array(vec![Value::array(vec![Value::Number(1.0)])]));
+        assert!(clone(vec![Value::Number(1.0)]).is_err());
+    }
+
     #[test]
     fn test_get() {
         // Test array get
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
                 Value::Number(3.0),
@@ -325,7 +375,7 @@ mod tests {
     fn test_set() {
         // Test array set
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
                 Value::Number(3.0),
@@ -336,6 +386,7 @@ mod tests {
         let result = set(args).unwrap();
 
         if let Value::Array(elements) = result {
+            let elements = elements.borrow();
             assert!(matches!(elements[1], Value::Number(99.0)));
         } else {
             panic!("Expected array result");
@@ -360,7 +411,7 @@ mod tests {
     fn test_slice() {
         // Test array slice
         let args = vec![
-            Value::Array(vec![
+            Value::array(vec![
                 Value::Number(1.0),
                 Value::Number(2.0),
                 Value::Number(3.0),
@@ -372,6 +423,7 @@ mod tests {
         let result = slice(args).unwrap();
 
         if let Value::Array(elements) = result {
+            let elements = elements.borrow();
             assert_eq!(elements.len(), 2);
             assert!(matches!(elements[0], Value::Number(2.0)));
             assert!(matches!(elements[1], Value::Number(3.0)));
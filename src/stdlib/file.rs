@@ -1,7 +1,6 @@
 use crate::interpreter::Value;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
 
 /// Read a file and return its contents as a string
 pub fn read(args: Vec<Value>) -> Result<Value, String> {
@@ -59,7 +58,6 @@ pub fn append(args: Vec<Value>) -> Result<Value, String> {
     };
 
     let result = std::fs::OpenOptions::new()
-        .write(true)
         .append(true)
         .create(true)
         .open(path)
@@ -116,10 +114,9 @@ pub fn file(args: Vec<Value>) -> Result<Value, String> {
         return Err("file: requires at least 2 arguments (path, mode)".to_string());
     }
 
-    let path = match &args[0] {
-        Value::String(s) => s,
-        _ => return Err("file: first argument must be a string filepath".to_string()),
-    };
+    if !matches!(&args[0], Value::String(_)) {
+        return Err("file: first argument must be a string filepath".to_string());
+    }
 
     let mode = match &args[1] {
         Value::String(s) => s,
@@ -150,7 +147,6 @@ pub fn file(args: Vec<Value>) -> Result<Value, String> {
 mod tests {
     use super::*;
     use tempfile::NamedTempFile;
-    use std::io::Read;
 
     #[test]
     fn test_file_operations() {
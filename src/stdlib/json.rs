@@ -1,5 +1,6 @@
 use crate::interpreter::Value;
 use std::iter::Peekable;
+use std::rc::Rc;
 use std::str::Chars;
 
 /// Parse JSON string to Rusty value: json_parse(string)
@@ -36,7 +37,7 @@ fn parse_json_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
         Some('t') => parse_json_true(chars),
         Some('f') => parse_json_false(chars),
         Some('n') => parse_json_null(chars),
-        Some(c) if c.is_digit(10) || *c == '-' => parse_json_number(chars),
+        Some(c) if c.is_ascii_digit() || *c == '-' => parse_json_number(chars),
         Some(c) => Err(format!("Unexpected character in JSON: {}", c)),
         None => Err("Unexpected end of JSON input".to_string()),
     }
@@ -63,7 +64,7 @@ fn parse_json_object(chars: &mut Peekable<Chars>) -> Result<Value, String> {
     if let Some('}') = chars.peek() {
         chars.next();
         // Return empty object as empty array in Rusty
-        return Ok(Value::Array(Vec::new()));
+        return Ok(Value::array(Vec::new()));
     }
 
     loop {
@@ -100,7 +101,7 @@ fn parse_json_object(chars: &mut Peekable<Chars>) -> Result<Value, String> {
                 let value = parse_json_value(chars)?;
 
                 // Add key-value pair to array
-                pairs.push(Value::Array(vec![Value::String(key_str), value]));
+                pairs.push(Value::array(vec![Value::String(key_str), value]));
 
                 // Skip whitespace
                 while let Some(c) = chars.peek() {
@@ -127,7 +128,7 @@ fn parse_json_object(chars: &mut Peekable<Chars>) -> Result<Value, String> {
                     Some('}') => {
                         chars.next();
                         // Return object as array of key-value pairs
-                        return Ok(Value::Array(pairs));
+                        return Ok(Value::array(pairs));
                     },
                     _ => return Err("Expected ',' or '}' in JSON object".to_string()),
                 }
@@ -160,7 +161,7 @@ fn parse_json_array(chars: &mut Peekable<Chars>) -> Result<Value, String> {
     // Check for empty array
     if let Some(']') = chars.peek() {
         chars.next();
-        return Ok(Value::Array(Vec::new()));
+        return Ok(Value::array(Vec::new()));
     }
 
     loop {
@@ -192,7 +193,7 @@ fn parse_json_array(chars: &mut Peekable<Chars>) -> Result<Value, String> {
             },
             Some(']') => {
                 chars.next();
-                return Ok(Value::Array(values));
+                return Ok(Value::array(values));
             },
             _ => return Err("Expected ',' or ']' in JSON array".to_string()),
         }
@@ -303,7 +304,7 @@ fn parse_json_number(chars: &mut Peekable<Chars>) -> Result<Value, String> {
 
     // Parse integer part
     while let Some(c) = chars.peek() {
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             number_str.push(chars.next().unwrap());
         } else {
             break;
@@ -316,7 +317,7 @@ fn parse_json_number(chars: &mut Peekable<Chars>) -> Result<Value, String> {
 
         let mut has_fraction = false;
         while let Some(c) = chars.peek() {
-            if c.is_digit(10) {
+            if c.is_ascii_digit() {
                 number_str.push(chars.next().unwrap());
                 has_fraction = true;
             } else {
@@ -343,7 +344,7 @@ fn parse_json_number(chars: &mut Peekable<Chars>) -> Result<Value, String> {
 
             let mut has_exponent = false;
             while let Some(c) = chars.peek() {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     number_str.push(chars.next().unwrap());
                     has_exponent = true;
                 } else {
@@ -390,6 +391,31 @@ fn escape_string(s: &str) -> String {
 
 /// Convert a Rusty Value to a JSON string
 fn value_to_json_string(value: &Value) -> Result<String, String> {
+    to_json(value, &mut Vec::new())
+}
+
+/// `open` holds the arrays and objects being converted further up, to
+/// reject a structure that contains itself.
+fn to_json(value: &Value, open: &mut Vec<*const ()>) -> Result<String, String> {
+    let key = match value {
+        Value::Array(elements) => Some(Rc::as_ptr(elements) as *const ()),
+        Value::Object(properties) => Some(Rc::as_ptr(properties) as *const ()),
+        _ => None,
+    };
+    if let Some(key) = key {
+        if open.contains(&key) {
+            return Err("stringify: cannot convert a circular structure to JSON".to_string());
+        }
+        open.push(key);
+    }
+    let result = container_to_json(value, open);
+    if key.is_some() {
+        open.pop();
+    }
+    result
+}
+
+fn container_to_json(value: &Value, open: &mut Vec<*const ()>) -> Result<String, String> {
     match value {
         Value::Number(n) => {
             // JSON doesn't allow Infinity or NaN
@@ -403,9 +429,11 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
         Value::Boolean(b) => Ok(b.to_string()),
         Value::Nil => Ok("null".to_string()),
         Value::Array(a) => {
+            let a = a.borrow();
             // If first element is an array of [key, value] pairs, treat as object
             if !a.is_empty() && a.iter().all(|e| {
                 if let Value::Array(pair) = e {
+                    let pair = pair.borrow();
                     pair.len() == 2 && matches!(pair[0], Value::String(_))
                 } else {
                     false
@@ -420,13 +448,14 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
                     }
 
                     if let Value::Array(pair) = element {
+                        let pair = pair.borrow();
                         if let Value::String(key) = &pair[0] {
                             // Add key
-                            result.push_str(&value_to_json_string(&Value::String(key.clone()))?);
+                            result.push_str(&to_json(&Value::String(key.clone()), open)?);
                             result.push_str(": ");
 
                             // Add value
-                            result.push_str(&value_to_json_string(&pair[1])?);
+                            result.push_str(&to_json(&pair[1], open)?);
                         }
                     }
                 }
@@ -442,7 +471,7 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
                         result.push_str(", ");
                     }
 
-                    result.push_str(&value_to_json_string(element)?);
+                    result.push_str(&to_json(element, open)?);
                 }
 
                 result.push(']');
@@ -450,15 +479,15 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
             }
         },
         Value::Object(obj) => {
-            // Convert to JSON object format
+            // Convert to JSON object format, with keys sorted for stable output
             let mut json_parts = Vec::new();
+            let obj = obj.borrow();
+            let mut entries: Vec<(&String, &Value)> = obj.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
 
-            for (key, value) in obj {
+            for (key, value) in entries {
                 let escaped_key = escape_string(key);
-                let value_str = match value_to_json_string(value) {
-                    Ok(s) => s,
-                    Err(e) => return Err(e),
-                };
+                let value_str = to_json(value, open)?;
 
                 json_parts.push(format!("\"{}\":{}", escaped_key, value_str));
             }
@@ -476,10 +505,7 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
                 // Skip functions in namespace serialization
                 if !matches!(value, Value::Function(_) | Value::NativeFunction(_)) {
                     let escaped_key = escape_string(key);
-                    let value_str = match value_to_json_string(value) {
-                        Ok(s) => s,
-                        Err(e) => return Err(e),
-                    };
+                    let value_str = to_json(value, open)?;
 
                     json_parts.push(format!("\"{}\":{}", escaped_key, value_str));
                 }
@@ -487,7 +513,11 @@ fn value_to_json_string(value: &Value) -> Result<String, String> {
 
             Ok(format!("{{{}}}", json_parts.join(",")))
         },
-        Value::Function(_) | Value::NativeFunction(_) => {
+        Value::Instance(instance) => {
+            // Serialize the instance's fields like a plain object
+            to_json(&Value::object(instance.borrow().fields.clone()), open)
+        },
+        Value::Function(_) | Value::NativeFunction(_) | Value::Class(_) => {
             // Functions are skipped or represented as null in JSON
             Ok("null".to_string())
         },
@@ -522,6 +552,7 @@ mod tests {
         let result = json_parse(args).unwrap();
 
         if let Value::Array(elements) = result {
+            let elements = elements.borrow();
             assert_eq!(elements.len(), 3);
             assert_eq!(elements[0], Value::Number(1.0));
             assert_eq!(elements[1], Value::Number(2.0));
@@ -543,7 +574,7 @@ mod tests {
         assert_eq!(str_result, Value::String("\"hello\"".to_string()));
 
         // Test stringifying array
-        let arr_args = vec![Value::Array(vec![
+        let arr_args = vec![Value::array(vec![
             Value::Number(1.0),
             Value::Number(2.0),
             Value::Number(3.0)
@@ -551,4 +582,30 @@ mod tests {
         let arr_result = json_stringify(arr_args).unwrap();
         assert_eq!(arr_result, Value::String("[1, 2, 3]".to_string()));
     }
+
+    #[test]
+    fn test_json_stringify_object_sorted_keys() {
+        let mut object = std::collections::HashMap::new();
+        object.insert("b".to_string(), Value::Number(2.0));
+        object.insert("a".to_string(), Value::Boolean(true));
+        let result = json_stringify(vec![Value::object(object)]).unwrap();
+        assert_eq!(result, Value::String("{\"a\":true,\"b\":2}".to_string()));
+    }
+
+    #[test]
+    fn test_json_stringify_rejects_cycles() {
+        let list = Value::array(vec![Value::Number(1.0)]);
+        if let Value::Array(elements) = &list {
+            elements.borrow_mut().push(list.clone());
+        }
+        assert_eq!(
+            json_stringify(vec![list]),
+            Err("stringify: cannot convert a circular structure to JSON".to_string())
+        );
+
+        // The same array twice side by side is not a cycle
+        let shared = Value::array(vec![Value::Number(1.0)]);
+        let pair = Value::array(vec![shared.clone(), shared]);
+        assert_eq!(json_stringify(vec![pair]).unwrap(), Value::String("[[1], [1]]".to_string()));
+    }
 }
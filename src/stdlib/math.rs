@@ -1,8 +1,11 @@
 use crate::interpreter::Value;
 use rand::Rng;
 
+/// Signature shared by all native math functions
+type MathFn = fn(Vec<Value>) -> Result<Value, String>;
+
 /// Returns a tuple of (name, arity, function) for all math functions
-pub fn get_functions() -> Vec<(&'static str, usize, fn(Vec<Value>) -> Result<Value, String>)> {
+pub fn get_functions() -> Vec<(&'static str, usize, MathFn)> {
     vec![
         ("random", 0, random),
         ("random_range", 2, random_range),
@@ -10,102 +10,131 @@ use crate::interpreter::{Value, NativeFunction};
 use std::rc::Rc;
 use std::collections::HashMap;
 
-/// Standard library for the Rusty language
+/// Standard library for the Rusty language. Each instance owns the
+/// namespaces it provides, so interpreters configured differently can run
+/// side by side.
 pub struct StdLib {
     functions: HashMap<String, Rc<NativeFunction>>,
 }
 
-// Global namespace constants - accessible from the getter functions
-static mut MATH_NAMESPACE: Option<Value> = None;
-static mut STRING_NAMESPACE: Option<Value> = None;
-static mut FILE_NAMESPACE: Option<Value> = None;
-static mut TIME_NAMESPACE: Option<Value> = None;
-static mut JSON_NAMESPACE: Option<Value> = None;
-static mut REGEX_NAMESPACE: Option<Value> = None;
-
-// Namespace accessor functions
-fn get_math_namespace(_args: Vec<Value>) -> Result<Value, String> {
-    unsafe {
-        if let Some(ref ns) = MATH_NAMESPACE {
-            Ok(ns.clone())
-        } else {
-            Err("Math namespace not initialized".to_string())
+/// The namespaces the standard library can provide
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    Math,
+    Array,
+    String,
+    File,
+    Time,
+    Json,
+    Regex,
+}
+
+impl Namespace {
+    /// Every standard namespace, in registration order
+    pub const ALL: [Namespace; 7] = [
+        Namespace::Math,
+        Namespace::Array,
+        Namespace::String,
+        Namespace::File,
+        Namespace::Time,
+        Namespace::Json,
+        Namespace::Regex,
+    ];
+
+    /// The name scripts use for the namespace
+    pub fn name(self) -> &'static str {
+        match self {
+            Namespace::Math => "Math",
+            Namespace::Array => "Array",
+            Namespace::String => "String",
+            Namespace::File => "File",
+            Namespace::Time => "Time",
+            Namespace::Json => "JSON",
+            Namespace::Regex => "Regex",
+        }
+    }
+
+    fn register(self, stdlib: &mut StdLib) {
+        match self {
+            Namespace::Math => stdlib.register_math_namespace(),
+            Namespace::Array => stdlib.register_array_namespace(),
+            Namespace::String => stdlib.register_string_namespace(),
+            Namespace::File => stdlib.register_file_namespace(),
+            Namespace::Time => stdlib.register_time_namespace(),
+            Namespace::Json => stdlib.register_json_namespace(),
+            Namespace::Regex => stdlib.register_regex_namespace(),
         }
     }
 }
 
-fn get_array_namespace(args: Vec<Value>) -> Result<Value, String> {
-    // Always create and return an array with the given arguments
-    return array::array(args);
+/// Chooses the namespaces a `StdLib` provides. It starts with all of them;
+/// `without` leaves one out, e.g. `File` for scripts that must not touch the
+/// file system.
+#[derive(Debug, Clone)]
+pub struct StdLibBuilder {
+    namespaces: Vec<Namespace>,
 }
 
-fn get_string_namespace(args: Vec<Value>) -> Result<Value, String> {
-    if args.is_empty() {
-        // If called with no arguments, return the namespace
-        unsafe {
-            if let Some(ref ns) = STRING_NAMESPACE {
-                return Ok(ns.clone());
-            } else {
-                return Err("String namespace not initialized".to_string());
-            }
+impl Default for StdLibBuilder {
+    fn default() -> Self {
+        StdLibBuilder {
+            namespaces: Namespace::ALL.to_vec(),
         }
-    } else {
-        // If called with arguments, convert to string
-        return string::as_string(vec![args[0].clone()]);
     }
 }
 
-fn get_file_namespace(_args: Vec<Value>) -> Result<Value, String> {
-    unsafe {
-        if let Some(ref ns) = FILE_NAMESPACE {
-            Ok(ns.clone())
-        } else {
-            Err("File namespace not initialized".to_string())
-        }
+impl StdLibBuilder {
+    /// A builder with no namespaces chosen
+    pub fn empty() -> Self {
+        StdLibBuilder { namespaces: Vec::new() }
     }
-}
 
-fn get_time_namespace(_args: Vec<Value>) -> Result<Value, String> {
-    unsafe {
-        if let Some(ref ns) = TIME_NAMESPACE {
-            Ok(ns.clone())
-        } else {
-            Err("Time namespace not initialized".to_string())
+    /// Provide `namespace`
+    pub fn with(mut self, namespace: Namespace) -> Self {
+        if !self.namespaces.contains(&namespace) {
+            self.namespaces.push(namespace);
         }
+        self
+    }
+
+    /// Leave `namespace` out
+    pub fn without(mut self, namespace: Namespace) -> Self {
+        self.namespaces.retain(|chosen| *chosen != namespace);
+        self
     }
-}
 
-fn get_json_namespace(_args: Vec<Value>) -> Result<Value, String> {
-    unsafe {
-        if let Some(ref ns) = JSON_NAMESPACE {
-            Ok(ns.clone())
-        } else {
-            Err("JSON namespace not initialized".to_string())
+    /// Build a standard library with the chosen namespaces
+    pub fn build(self) -> StdLib {
+        let mut stdlib = StdLib {
+            functions: HashMap::new(),
+        };
+        for namespace in self.namespaces {
+            namespace.register(&mut stdlib);
         }
+        stdlib
     }
 }
 
-fn get_regex_namespace(_args: Vec<Value>) -> Result<Value, String> {
-    unsafe {
-        if let Some(ref ns) = REGEX_NAMESPACE {
-            Ok(ns.clone())
-        } else {
-            Err("Regex namespace not initialized".to_string())
-        }
+fn get_array_namespace(args: Vec<Value>) -> Result<Value, String> {
+    // Always create and return an array with the given arguments
+    array::array(args)
+}
+
+impl Default for StdLib {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl StdLib {
     /// Create a new standard library with all available functions
     pub fn new() -> Self {
-        let mut stdlib = StdLib {
-            functions: HashMap::new(),
-        };
-
-        // Register namespaces for OOP style
-        stdlib.register_all_namespaces();
+        Self::builder().build()
+    }
 
-        stdlib
+    /// Start choosing the namespaces a standard library provides
+    pub fn builder() -> StdLibBuilder {
+        StdLibBuilder::default()
     }
 
     /// Get all standard library functions
@@ -113,39 +142,14 @@ impl StdLib {
         &self.functions
     }
 
-    /// Register all namespaces
-    fn register_all_namespaces(&mut self) {
-        // Register Math namespace
-        self.register_math_namespace();
-
-        // Register Array namespace
-        self.register_array_namespace();
-
-        // Register String namespace
-        self.register_string_namespace();
-
-        // Register File namespace
-        self.register_file_namespace();
-
-        // Register Time namespace
-        self.register_time_namespace();
-
-        // Register JSON namespace
-        self.register_json_namespace();
-
-        // Register Regex namespace
-        self.register_regex_namespace();
-    }
-
     /// Register a native function in the standard library
-    fn register(&mut self, name: &str, arity: usize, func: fn(Vec<Value>) -> Result<Value, String>) {
-        let native_fn = Rc::new(NativeFunction {
-            name: name.to_string(),
-            arity,
-            function: func,
-        });
+    fn register(&mut self, name: &str, arity: usize, func: impl Fn(Vec<Value>) -> Result<Value, String> + 'static) {
+        self.functions.insert(name.to_string(), NativeFunction::new(name.to_string(), arity, func));
+    }
 
-        self.functions.insert(name.to_string(), native_fn);
+    /// Register the accessor that returns `namespace`, e.g. `Math()`
+    fn register_namespace(&mut self, name: &str, namespace: Value) {
+        self.register(name, 0, move |_| Ok(namespace.clone()));
     }
 
     /// Register a Math namespace with math constants and functions
@@ -162,8 +166,8 @@ impl StdLib {
         for (name, arity, func) in math::get_functions() {
             let math_fn = Rc::new(NativeFunction {
                 name: format!("Math.{}", name),
-                arity: arity,
-                function: func,
+                arity,
+                function: Rc::new(func),
             });
 
             if let Value::Namespace(_, props) = &mut math_namespace {
@@ -178,13 +182,8 @@ impl StdLib {
         self.register("Math.PI", 0, |_| Ok(Value::Number(std::f64::consts::PI)));
         self.register("Math.E", 0, |_| Ok(Value::Number(std::f64::consts::E)));
 
-        // Store the namespace in the global variable
-        unsafe {
-            MATH_NAMESPACE = Some(math_namespace);
-        }
-
         // Register the Math namespace accessor function
-        self.register("Math", 0, get_math_namespace);
+        self.register_namespace("Math", math_namespace);
     }
 
     /// Create an Array namespace with all array functions
@@ -211,25 +210,25 @@ impl StdLib {
         let len_fn = Rc::new(NativeFunction {
             name: "String.length".to_string(),
             arity: 1,
-            function: string::len,
+            function: Rc::new(string::len),
         });
 
         let upper_fn = Rc::new(NativeFunction {
             name: "String.upper".to_string(),
             arity: 1,
-            function: string::upper,
+            function: Rc::new(string::upper),
         });
 
         let lower_fn = Rc::new(NativeFunction {
             name: "String.lower".to_string(),
             arity: 1,
-            function: string::lower,
+            function: Rc::new(string::lower),
         });
 
         let as_string_fn = Rc::new(NativeFunction {
             name: "String.string".to_string(),
             arity: 1,
-            function: string::as_string,
+            function: Rc::new(string::as_string),
         });
 
         // Add methods to String namespace
@@ -240,13 +239,15 @@ impl StdLib {
             props.insert("string".to_string(), Value::NativeFunction(as_string_fn.clone()));
         }
 
-        // Store the namespace in the global variable
-        unsafe {
-            STRING_NAMESPACE = Some(string_namespace);
-        }
-
-        // Register the String namespace accessor function
-        self.register("String", 0, get_string_namespace);
+        // Register the String namespace accessor function. Called with an
+        // argument, it converts the argument to a string instead.
+        self.register("String", 0, move |args: Vec<Value>| {
+            if args.is_empty() {
+                Ok(string_namespace.clone())
+            } else {
+                string::as_string(vec![args[0].clone()])
+            }
+        });
 
         // Also register static methods on String namespace
         self.register("String.length", 1, string::len);
@@ -263,31 +264,31 @@ impl StdLib {
         let read_fn = Rc::new(NativeFunction {
             name: "File.read".to_string(),
             arity: 1,
-            function: file::read,
+            function: Rc::new(file::read),
         });
 
         let write_fn = Rc::new(NativeFunction {
             name: "File.write".to_string(),
             arity: 2,
-            function: file::write,
+            function: Rc::new(file::write),
         });
 
         let append_fn = Rc::new(NativeFunction {
             name: "File.append".to_string(),
             arity: 2,
-            function: file::append,
+            function: Rc::new(file::append),
         });
 
         let exists_fn = Rc::new(NativeFunction {
             name: "File.exists".to_string(),
             arity: 1,
-            function: file::exists,
+            function: Rc::new(file::exists),
         });
 
         let delete_fn = Rc::new(NativeFunction {
             name: "File.delete".to_string(),
             arity: 1,
-            function: file::delete,
+            function: Rc::new(file::delete),
         });
 
         // Add methods to File namespace
@@ -299,13 +300,8 @@ impl StdLib {
             props.insert("delete".to_string(), Value::NativeFunction(delete_fn));
         }
 
-        // Store the namespace in the global variable
-        unsafe {
-            FILE_NAMESPACE = Some(file_namespace);
-        }
-
         // Register the File namespace accessor function
-        self.register("File", 0, get_file_namespace);
+        self.register_namespace("File", file_namespace);
     }
 
     /// Register a Time namespace
@@ -316,7 +312,7 @@ impl StdLib {
         let now_fn = Rc::new(NativeFunction {
             name: "Time.now".to_string(),
             arity: 0,
-            function: time::time,
+            function: Rc::new(time::time),
         });
 
         // Add methods to Time namespace
@@ -324,13 +320,8 @@ impl StdLib {
             props.insert("now".to_string(), Value::NativeFunction(now_fn));
         }
 
-        // Store the namespace in the global variable
-        unsafe {
-            TIME_NAMESPACE = Some(time_namespace);
-        }
-
         // Register the Time namespace accessor function
-        self.register("Time", 0, get_time_namespace);
+        self.register_namespace("Time", time_namespace);
     }
 
     /// Register a JSON namespace
@@ -341,13 +332,13 @@ impl StdLib {
         let parse_fn = Rc::new(NativeFunction {
             name: "JSON.parse".to_string(),
             arity: 1,
-            function: json::json_parse,
+            function: Rc::new(json::json_parse),
         });
 
         let stringify_fn = Rc::new(NativeFunction {
             name: "JSON.stringify".to_string(),
             arity: 1,
-            function: json::json_stringify,
+            function: Rc::new(json::json_stringify),
         });
 
         // Add methods to JSON namespace
@@ -356,13 +347,8 @@ impl StdLib {
             props.insert("stringify".to_string(), Value::NativeFunction(stringify_fn));
         }
 
-        // Store the namespace in the global variable
-        unsafe {
-            JSON_NAMESPACE = Some(json_namespace);
-        }
-
         // Register the JSON namespace accessor function
-        self.register("JSON", 0, get_json_namespace);
+        self.register_namespace("JSON", json_namespace);
     }
 
     /// Register a Regex namespace
@@ -373,49 +359,49 @@ impl StdLib {
         let new_fn = Rc::new(NativeFunction {
             name: "Regex.new".to_string(),
             arity: 1,
-            function: regex::regex_new,
+            function: Rc::new(regex::regex_new),
         });
 
         let test_fn = Rc::new(NativeFunction {
             name: "Regex.test".to_string(),
             arity: 2,
-            function: regex::regex_test,
+            function: Rc::new(regex::regex_test),
         });
 
         let match_fn = Rc::new(NativeFunction {
             name: "Regex.match".to_string(),
             arity: 2,
-            function: regex::regex_match_all,
+            function: Rc::new(regex::regex_match_all),
         });
 
         let replace_fn = Rc::new(NativeFunction {
             name: "Regex.replace".to_string(),
             arity: 3,
-            function: regex::regex_replace_all,
+            function: Rc::new(regex::regex_replace_all),
         });
 
         let split_fn = Rc::new(NativeFunction {
             name: "Regex.split".to_string(),
             arity: 2,
-            function: regex::regex_split,
+            function: Rc::new(regex::regex_split),
         });
 
         let capture_fn = Rc::new(NativeFunction {
             name: "Regex.capture".to_string(),
             arity: 2,
-            function: regex::regex_capture,
+            function: Rc::new(regex::regex_capture),
         });
 
         let is_valid_fn = Rc::new(NativeFunction {
             name: "Regex.is_valid".to_string(),
             arity: 1,
-            function: regex::regex_is_valid,
+            function: Rc::new(regex::regex_is_valid),
         });
 
         let escape_fn = Rc::new(NativeFunction {
             name: "Regex.escape".to_string(),
             arity: 1,
-            function: regex::regex_escape,
+            function: Rc::new(regex::regex_escape),
         });
 
         // Add methods to Regex namespace
@@ -430,12 +416,47 @@ impl StdLib {
             props.insert("escape".to_string(), Value::NativeFunction(escape_fn));
         }
 
-        // Store the namespace in the global variable
-        unsafe {
-            REGEX_NAMESPACE = Some(regex_namespace);
+        // Register the Regex namespace accessor function
+        self.register_namespace("Regex", regex_namespace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(stdlib: &StdLib, name: &str) -> Option<Value> {
+        stdlib.get_functions().get(name).map(|accessor| (accessor.function)(Vec::new()).unwrap())
+    }
+
+    #[test]
+    fn test_builder_chooses_namespaces() {
+        let stdlib = StdLib::new();
+        for namespace in Namespace::ALL {
+            assert!(stdlib.get_functions().contains_key(namespace.name()));
         }
 
-        // Register the Regex namespace accessor function
-        self.register("Regex", 0, get_regex_namespace);
+        let stdlib = StdLib::builder().without(Namespace::File).build();
+        assert!(!stdlib.get_functions().contains_key("File"));
+        assert!(matches!(namespace(&stdlib, "Math"), Some(Value::Namespace(..))));
+
+        let stdlib = StdLibBuilder::empty().with(Namespace::Json).build();
+        let names: Vec<&String> = stdlib.get_functions().keys().collect();
+        assert_eq!(names, vec!["JSON"]);
+    }
+
+    #[test]
+    fn test_accessor_returns_owned_namespace() {
+        let stdlib = StdLib::new();
+        let Some(Value::Namespace(name, properties)) = namespace(&stdlib, "Math") else {
+            panic!("expected the Math namespace")
+        };
+        assert_eq!(name, "Math");
+        assert_eq!(properties.get("PI"), Some(&Value::Number(std::f64::consts::PI)));
+
+        // String() returns its namespace, String(x) converts x
+        let string = &stdlib.get_functions()["String"];
+        assert!(matches!(namespace(&stdlib, "String"), Some(Value::Namespace(name, _)) if name == "String"));
+        assert_eq!((string.function)(vec![Value::Number(3.0)]).unwrap(), Value::String("3".to_string()));
     }
 }
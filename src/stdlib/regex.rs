@@ -1,54 +1,6 @@
 use crate::interpreter::Value;
 use regex::Regex;
 
-/// Process the pattern string to handle backslash escaping
-/// In the rusty language, \\d is parsed as \d, but Rust's regex needs \\d
-/// to represent the digit metacharacter
-fn process_pattern(pattern: &str) -> String {
-    // Basic implementation - handle common metacharacters manually
-    // A proper lexer-based implementation would be more robust
-    let mut result = String::new();
-    let mut chars = pattern.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        if c == '\\' {
-            if let Some(next) = chars.peek() {
-                // These are common regex metacharacters that need special handling
-                match next {
-                    'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'b' | 'B' |
-                    'n' | 'r' | 't' | '0' | 'A' | 'Z' | 'z' | 'G' |
-                    'p' | 'P' => {
-                        // Keep the backslash for these metacharacters
-                        result.push('\\');
-                        result.push(*next);
-                        chars.next(); // Consume the next character
-                    },
-                    // Handle hex and unicode escapes
-                    'x' | 'u' | 'U' => {
-                        result.push('\\');
-                        result.push(*next);
-                        chars.next(); // Consume the next character
-                    },
-                    // For any other character after a backslash, just copy both
-                    _ => {
-                        result.push('\\');
-                        result.push(*next);
-                        chars.next(); // Consume the next character
-                    }
-                }
-            } else {
-                // Trailing backslash, just copy it
-                result.push('\\');
-            }
-        } else {
-            // Not a backslash, just copy the character
-            result.push(c);
-        }
-    }
-
-    result
-}
-
 /// Creates a new Regex pattern
 ///
 /// Args:
@@ -66,18 +18,16 @@ pub fn regex_new(args: Vec<Value>) -> Result<Value, String> {
         _ => return Err("regex_new expects a string pattern".to_string()),
     };
 
-    // Process the pattern to handle escaping properly
-    let processed_pattern = process_pattern(pattern);
-
-    // Compile the regex pattern with the processed pattern
-    match Regex::new(&processed_pattern) {
+    // String literals have already decoded their escapes, so the pattern is
+    // compiled exactly as written (use raw strings like r"\d+" to avoid doubling backslashes)
+    match Regex::new(pattern) {
         Ok(_) => {
-            // Store the original and processed pattern as a special array
+            // Store the pattern and its tagged copy as a special array
             let mut result = Vec::new();
             result.push(Value::String(pattern.clone()));
-            result.push(Value::String(format!("__REGEX__{}", processed_pattern)));
+            result.push(Value::String(format!("__REGEX__{}", pattern)));
 
-            Ok(Value::Array(result))
+            Ok(Value::array(result))
         },
         Err(e) => Err(format!("Invalid regex pattern: {}", e)),
     }
@@ -86,6 +36,7 @@ pub fn regex_new(args: Vec<Value>) -> Result<Value, String> {
 /// Checks if the given value is a regex
 fn is_regex(value: &Value) -> bool {
     if let Value::Array(arr) = value {
+        let arr = arr.borrow();
         if arr.len() == 2 {
             if let Value::String(_) = &arr[0] {
                 if let Value::String(marker) = &arr[1] {
@@ -104,7 +55,7 @@ fn get_processed_pattern(regex_obj: &Value) -> Result<String, String> {
     }
 
     if let Value::Array(arr) = regex_obj {
-        if let Value::String(marker) = &arr[1] {
+        if let Value::String(marker) = &arr.borrow()[1] {
             if let Some(pattern) = marker.strip_prefix("__REGEX__") {
                 return Ok(pattern.to_string());
             }
@@ -181,7 +132,7 @@ pub fn regex_match_all(args: Vec<Value>) -> Result<Value, String> {
         matches.push(Value::String(cap.as_str().to_string()));
     }
 
-    Ok(Value::Array(matches))
+    Ok(Value::array(matches))
 }
 
 /// Replaces all occurrences of a pattern in a string
@@ -249,7 +200,7 @@ pub fn regex_split(args: Vec<Value>) -> Result<Value, String> {
         .map(|s| Value::String(s.to_string()))
         .collect();
 
-    Ok(Value::Array(parts))
+    Ok(Value::array(parts))
 }
 
 /// Gets an array of capture groups for the first match
@@ -296,7 +247,7 @@ pub fn regex_capture(args: Vec<Value>) -> Result<Value, String> {
             }
         }
 
-        Ok(Value::Array(groups))
+        Ok(Value::array(groups))
     } else {
         Ok(Value::Nil)
     }
@@ -319,8 +270,7 @@ pub fn regex_is_valid(args: Vec<Value>) -> Result<Value, String> {
         _ => return Err("regex_is_valid expects a string pattern".to_string()),
     };
 
-    let processed_pattern = process_pattern(pattern);
-    match Regex::new(&processed_pattern) {
+    match Regex::new(pattern) {
         Ok(_) => Ok(Value::Boolean(true)),
         Err(_) => Ok(Value::Boolean(false)),
     }
@@ -362,27 +312,6 @@ mod tests {
         regex_new(args).unwrap()
     }
 
-    #[test]
-    fn test_process_pattern() {
-        // Test basic character escaping
-        assert_eq!(process_pattern("abc"), "abc");
-
-        // Test digit metacharacter
-        assert_eq!(process_pattern("\\d+"), "\\d+");
-
-        // Test word metacharacter
-        assert_eq!(process_pattern("\\w+"), "\\w+");
-
-        // Test space metacharacter
-        assert_eq!(process_pattern("\\s+"), "\\s+");
-
-        // Test backslash escaping
-        assert_eq!(process_pattern("\\\\"), "\\\\");
-
-        // Test dot escaping
-        assert_eq!(process_pattern("\\."), "\\.");
-    }
-
     #[test]
     fn test_regex_new() {
         // Test with valid pattern
@@ -390,6 +319,7 @@ mod tests {
         let result = regex_new(args).unwrap();
 
         if let Value::Array(arr) = result {
+            let arr = arr.borrow();
             assert_eq!(arr.len(), 2);
 
             if let Value::String(pattern) = &arr[0] {
@@ -433,10 +363,8 @@ mod tests {
         assert!(!is_regex(&Value::Nil));
 
         // Test with array that's not a regex
-        let mut arr = Vec::new();
-        arr.push(string_value("not"));
-        arr.push(string_value("a regex"));
-        assert!(!is_regex(&Value::Array(arr)));
+        let arr = vec![string_value("not"), string_value("a regex")];
+        assert!(!is_regex(&Value::array(arr)));
     }
 
     #[test]
@@ -473,6 +401,7 @@ mod tests {
         let result = regex_match_all(args).unwrap();
 
         if let Value::Array(matches) = result {
+            let matches = matches.borrow();
             assert_eq!(matches.len(), 2);
             assert_eq!(matches[0], string_value("123"));
             assert_eq!(matches[1], string_value("456"));
@@ -485,6 +414,7 @@ mod tests {
         let result = regex_match_all(args).unwrap();
 
         if let Value::Array(matches) = result {
+            let matches = matches.borrow();
             assert_eq!(matches.len(), 0);
         } else {
             panic!("Expected result to be an array");
@@ -519,6 +449,7 @@ mod tests {
         let result = regex_split(args).unwrap();
 
         if let Value::Array(parts) = result {
+            let parts = parts.borrow();
             assert_eq!(parts.len(), 3);
             assert_eq!(parts[0], string_value("a"));
             assert_eq!(parts[1], string_value("b"));
@@ -532,6 +463,7 @@ mod tests {
         let result = regex_split(args).unwrap();
 
         if let Value::Array(parts) = result {
+            let parts = parts.borrow();
             assert_eq!(parts.len(), 1);
             assert_eq!(parts[0], string_value("abc"));
         } else {
@@ -547,6 +479,7 @@ mod tests {
         let result = regex_capture(args).unwrap();
 
         if let Value::Array(groups) = result {
+            let groups = groups.borrow();
             assert_eq!(groups.len(), 3);
             assert_eq!(groups[0], string_value("width=100")); // Full match
             assert_eq!(groups[1], string_value("width"));     // First group
@@ -593,6 +526,7 @@ mod tests {
         let result = regex_match_all(args).unwrap();
 
         if let Value::Array(matches) = result {
+            let matches = matches.borrow();
             assert_eq!(matches.len(), 2);
             assert_eq!(matches[0], string_value("123"));
             assert_eq!(matches[1], string_value("456"));
@@ -606,6 +540,7 @@ mod tests {
         let result = regex_match_all(args).unwrap();
 
         if let Value::Array(matches) = result {
+            let matches = matches.borrow();
             assert_eq!(matches.len(), 3);
             assert_eq!(matches[0], string_value("abc"));
             assert_eq!(matches[1], string_value("123"));
@@ -620,6 +555,7 @@ mod tests {
         let result = regex_match_all(args).unwrap();
 
         if let Value::Array(matches) = result {
+            let matches = matches.borrow();
             assert_eq!(matches.len(), 2);
             assert_eq!(matches[0], string_value(" "));
             assert_eq!(matches[1], string_value("  "));
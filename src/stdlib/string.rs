@@ -4,7 +4,7 @@ use crate::interpreter::Value;
 pub fn len(args: Vec<Value>) -> Result<Value, String> {
     match &args[0] {
         Value::String(s) => Ok(Value::Number(s.len() as f64)),
-        Value::Array(a) => Ok(Value::Number(a.len() as f64)),
+        Value::Array(a) => Ok(Value::Number(a.borrow().len() as f64)),
         Value::Object(_) => Ok(Value::Number(0.0)), // Temporary implementation
         Value::Namespace(_, _) => Ok(Value::Number(0.0)), // Temporary implementation
         _ => Err("len: argument must be a string or array".to_string()),
@@ -63,7 +63,7 @@ pub fn as_string(args: Vec<Value>) -> Result<Value, String> {
         Value::Array(a) => {
             // Convert array to a simple string representation
             let mut result = String::from("[");
-            for (i, element) in a.iter().enumerate() {
+            for (i, element) in a.borrow().iter().enumerate() {
                 if i > 0 {
                     result.push_str(", ");
                 }
@@ -77,6 +77,8 @@ pub fn as_string(args: Vec<Value>) -> Result<Value, String> {
                     Value::Array(_) => result.push_str("[...]"),
                     Value::Object(_) => result.push_str("{...}"), // Simple representation for objects
                     Value::Namespace(name, _) => result.push_str(&format!("[Namespace: {}]", name)), // Simple representation for namespaces
+                    Value::Class(class) => result.push_str(&format!("<class {}>", class.name)),
+                    Value::Instance(instance) => result.push_str(&format!("<{} instance>", instance.borrow().class.name)),
                     Value::Function(_) | Value::NativeFunction(_) => result.push_str("<function>"),
                 }
             }
@@ -85,6 +87,8 @@ pub fn as_string(args: Vec<Value>) -> Result<Value, String> {
         },
         Value::Object(_) => Ok(Value::String("{object}".to_string())),
         Value::Namespace(name, _) => Ok(Value::String(format!("[Namespace: {}]", name))),
+        Value::Class(class) => Ok(Value::String(format!("<class {}>", class.name))),
+        Value::Instance(instance) => Ok(Value::String(format!("<{} instance>", instance.borrow().class.name))),
     }
 }
 
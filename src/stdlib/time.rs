@@ -20,11 +20,7 @@ mod tests {
     fn test_time() {
         let args = vec![];
         let result = time(args).unwrap();
-        if let Value::Number(_) = result {
-            // Just check that it returns a number, actual value will vary
-            assert!(true);
-        } else {
-            panic!("Expected number result");
-        }
+        // Just check that it returns a number, actual value will vary
+        assert!(matches!(result, Value::Number(_)), "Expected number result");
     }
 }